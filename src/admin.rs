@@ -0,0 +1,383 @@
+use crate::config::{Config, Inbound, Protocol, User};
+use crate::traffic::{self, Stats, Traffic};
+
+use std::sync::Arc;
+
+use cidr::IpCidr;
+use serde::Serialize;
+use uuid::Uuid;
+use worker::*;
+
+// worker bindings used by the admin api
+const KV_BINDING: &str = "TUNL";
+const TOKEN_SECRET: &str = "ADMIN_TOKEN";
+const CONFIG_KEY: &str = "config";
+
+#[derive(Serialize)]
+struct UserEntry<'a> {
+    inbound: usize,
+    path: &'a str,
+    protocol: &'a Protocol,
+    #[serde(flatten)]
+    user: &'a User,
+}
+
+#[derive(Serialize)]
+struct StatsEntry {
+    key: String,
+    #[serde(flatten)]
+    stats: Stats,
+}
+
+#[derive(Serialize)]
+struct Route<'a> {
+    r#match: &'a [IpCidr],
+    /// protocol of the outbound, missing for balancers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound: Option<&'a Protocol>,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    tag: &'a str,
+}
+
+#[derive(Serialize)]
+struct RoutingTable<'a> {
+    routes: Vec<Route<'a>>,
+    default: Protocol,
+}
+
+/// loads the config persisted by the admin api, falls back to the bundled one
+/// if there is no kv binding or nothing has been stored yet
+pub async fn load_config(env: &Env, fallback: Arc<Config>) -> Arc<Config> {
+    let kv = match env.kv(KV_BINDING) {
+        Ok(kv) => kv,
+        Err(_) => return fallback,
+    };
+
+    match kv.get(CONFIG_KEY).json::<Config>().await {
+        Ok(Some(config)) => Arc::new(config),
+        Ok(None) => fallback,
+        Err(e) => {
+            console_log!("[admin]: failed to load config from kv: {e}");
+            fallback
+        }
+    }
+}
+
+pub async fn handle(mut req: Request, env: Env, config: Arc<Config>) -> Result<Response> {
+    if !authorized(&req, &env)? {
+        return Response::error("unauthorized", 401);
+    }
+
+    let prefix = config.admin.as_ref().map(|a| a.path.len()).unwrap_or(0);
+    let path = req.path();
+    let segments: Vec<&str> = path[prefix..]
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let mut config = (*config).clone();
+    let result = match (req.method(), segments.as_slice()) {
+        (Method::Get, ["inbounds"]) => return Response::from_json(&config.inbound),
+        (Method::Get, ["users"]) => return Response::from_json(&list_users(&config)),
+        (Method::Get, ["routes"]) => return Response::from_json(&routing_table(&config)),
+        (Method::Get, ["validate"]) => return Response::from_json(&config.validate()),
+        (Method::Get, ["schema"]) => return Response::from_json(&schemars::schema_for!(Config)),
+        (Method::Get, ["stats"]) => return traffic_stats(&env, &config).await,
+        (Method::Post, ["inbounds", index, "users"]) => {
+            let user = match req.json::<User>().await {
+                Ok(user) => user,
+                Err(e) => return Response::error(format!("invalid user: {e}"), 400),
+            };
+            add_user(&mut config, index, user)
+        }
+        (Method::Post, ["inbounds", index, "users", name, "disable"]) => {
+            set_disabled(&mut config, index, name, true)
+        }
+        (Method::Post, ["inbounds", index, "users", name, "enable"]) => {
+            set_disabled(&mut config, index, name, false)
+        }
+        (Method::Post, ["inbounds", index, "users", name, "rotate"]) => {
+            rotate_user(&mut config, index, name)
+        }
+        (Method::Delete, ["inbounds", index, "users", name]) => {
+            delete_user(&mut config, index, name)
+        }
+        _ => return Response::error("not found", 404),
+    };
+
+    match result {
+        Ok(()) => {
+            save_config(&env, &config).await?;
+            Response::from_json(&config.inbound)
+        }
+        Err(e) => Response::error(e, 400),
+    }
+}
+
+fn authorized(req: &Request, env: &Env) -> Result<bool> {
+    let token = match env.secret(TOKEN_SECRET) {
+        Ok(token) => token.to_string(),
+        Err(_) => return Ok(false),
+    };
+    if token.is_empty() {
+        return Ok(false);
+    }
+
+    let header = req.headers().get("Authorization")?.unwrap_or_default();
+    let given = header.strip_prefix("Bearer ").unwrap_or_default();
+
+    Ok(crate::common::constant_time_eq(given, &token))
+}
+
+async fn save_config(env: &Env, config: &Config) -> Result<()> {
+    let buf = serde_json::to_string(config)?;
+    env.kv(KV_BINDING)?.put(CONFIG_KEY, buf)?.execute().await?;
+    Ok(())
+}
+
+fn list_users(config: &Config) -> Vec<UserEntry> {
+    config
+        .inbound
+        .iter()
+        .enumerate()
+        .flat_map(|(i, inbound)| {
+            inbound.users.iter().map(move |user| UserEntry {
+                inbound: i,
+                path: &inbound.path,
+                protocol: &inbound.protocol,
+                user,
+            })
+        })
+        .collect()
+}
+
+async fn traffic_stats(env: &Env, config: &Config) -> Result<Response> {
+    let traffic = match Traffic::new(env) {
+        Some(traffic) => traffic,
+        None => return Response::error("traffic accounting is disabled", 503),
+    };
+
+    let mut entries = Vec::new();
+    for inbound in &config.inbound {
+        let disabled = inbound.users.iter().filter(|u| u.disabled).cloned();
+        let mut keys = vec![traffic::inbound_key(inbound)];
+        keys.extend(
+            inbound
+                .users()
+                .into_iter()
+                .chain(disabled)
+                .map(|user| traffic::user_key(inbound, &user)),
+        );
+
+        for key in keys {
+            let stats = traffic.stats(&key).await?;
+            entries.push(StatsEntry { key, stats });
+        }
+    }
+
+    Response::from_json(&entries)
+}
+
+fn routing_table(config: &Config) -> RoutingTable {
+    let rules = config.routing.iter().map(|rule| Route {
+        r#match: &rule.r#match,
+        outbound: config
+            .outbound_by_tag(&rule.outbound)
+            .map(|outbound| &outbound.protocol),
+        tag: &rule.outbound,
+    });
+    let main = Route {
+        r#match: &config.outbound.r#match,
+        outbound: Some(&config.outbound.protocol),
+        tag: &config.outbound.tag,
+    };
+
+    RoutingTable {
+        routes: rules.chain(std::iter::once(main)).collect(),
+        default: Protocol::Freedom,
+    }
+}
+
+fn inbound_mut<'a>(
+    config: &'a mut Config,
+    index: &str,
+) -> std::result::Result<&'a mut Inbound, String> {
+    index
+        .parse::<usize>()
+        .ok()
+        .and_then(|i| config.inbound.get_mut(i))
+        .ok_or(format!("inbound {index} not found"))
+}
+
+fn add_user(config: &mut Config, index: &str, user: User) -> std::result::Result<(), String> {
+    let inbound = inbound_mut(config, index)?;
+    if user.name.is_empty() || user.name == Inbound::DEFAULT_USER {
+        return Err(format!("invalid user name {:?}", user.name));
+    }
+    if inbound.users.iter().any(|u| u.name == user.name) {
+        return Err(format!("user {} already exists", user.name));
+    }
+    if !inbound.has_credential(&user) {
+        return Err(missing_credential(inbound, &user.name));
+    }
+
+    inbound.users.push(user);
+    Ok(())
+}
+
+fn set_disabled(
+    config: &mut Config,
+    index: &str,
+    name: &str,
+    disabled: bool,
+) -> std::result::Result<(), String> {
+    let inbound = inbound_mut(config, index)?;
+    let user = inbound
+        .users
+        .iter_mut()
+        .find(|u| u.name == name)
+        .ok_or(format!("user {name} not found"))?;
+
+    user.disabled = disabled;
+    Ok(())
+}
+
+fn delete_user(config: &mut Config, index: &str, name: &str) -> std::result::Result<(), String> {
+    let inbound = inbound_mut(config, index)?;
+    let len = inbound.users.len();
+    inbound.users.retain(|u| u.name != name);
+
+    if inbound.users.len() == len {
+        return Err(format!("user {name} not found"));
+    }
+    Ok(())
+}
+
+fn rotate_user(config: &mut Config, index: &str, name: &str) -> std::result::Result<(), String> {
+    let inbound = inbound_mut(config, index)?;
+
+    let mut uuid = [0u8; 16];
+    let mut password = [0u8; 16];
+    getrandom::getrandom(&mut uuid).map_err(|e| e.to_string())?;
+    getrandom::getrandom(&mut password).map_err(|e| e.to_string())?;
+    let uuid = uuid::Builder::from_random_bytes(uuid).into_uuid();
+    let password = crate::hex!(password);
+
+    // the default user lives in the top level fields of the inbound
+    if name == Inbound::DEFAULT_USER {
+        if inbound.users().iter().all(|u| u.name != name) {
+            return Err(missing_credential(inbound, name));
+        }
+        rotate(&mut inbound.uuid, &mut inbound.password, uuid, password);
+        return Ok(());
+    }
+
+    let i = inbound
+        .users
+        .iter()
+        .position(|u| u.name == name)
+        .ok_or(format!("user {name} not found"))?;
+    if !inbound.has_credential(&inbound.users[i]) {
+        return Err(missing_credential(inbound, name));
+    }
+
+    let user = &mut inbound.users[i];
+    rotate(&mut user.uuid, &mut user.password, uuid, password);
+    Ok(())
+}
+
+fn missing_credential(inbound: &Inbound, name: &str) -> String {
+    format!(
+        "user {name} has no {} for {:?}",
+        inbound.protocol.credential().unwrap_or_default(),
+        inbound.protocol
+    )
+}
+
+fn rotate(uuid: &mut Uuid, password: &mut String, new_uuid: Uuid, new_password: String) {
+    // only rotate the credential which is actually in use
+    if !uuid.is_nil() {
+        *uuid = new_uuid;
+    }
+    if !password.is_empty() {
+        *password = new_password;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        )
+    }
+
+    #[test]
+    fn test_manage_users() {
+        let mut config = config();
+        let user = User {
+            name: "alice".to_string(),
+            uuid: uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894"),
+            ..Default::default()
+        };
+
+        assert!(add_user(&mut config, "0", user.clone()).is_ok());
+        assert!(add_user(&mut config, "0", user.clone()).is_err());
+        assert!(add_user(&mut config, "1", user).is_err());
+        assert_eq!(list_users(&config).len(), 1);
+
+        assert!(set_disabled(&mut config, "0", "alice", true).is_ok());
+        assert_eq!(config.inbound[0].users().len(), 1);
+
+        assert!(rotate_user(&mut config, "0", "alice").is_ok());
+        assert_ne!(
+            config.inbound[0].users[0].uuid,
+            uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894")
+        );
+        assert!(config.inbound[0].users[0].password.is_empty());
+
+        assert!(rotate_user(&mut config, "0", Inbound::DEFAULT_USER).is_ok());
+        assert_ne!(
+            config.inbound[0].uuid,
+            uuid::uuid!("0fbf4f81-2598-4b6a-a623-0ead4cb9efa8")
+        );
+
+        assert!(delete_user(&mut config, "0", "alice").is_ok());
+        assert!(delete_user(&mut config, "0", "alice").is_err());
+    }
+
+    #[test]
+    fn test_missing_credential() {
+        let mut config = config();
+        let user = User {
+            name: "bob".to_string(),
+            password: "secret".to_string(),
+            ..Default::default()
+        };
+        assert!(add_user(&mut config, "0", user.clone()).is_err());
+
+        // a user which got in without a uuid can't be rotated into one either
+        config.inbound[0].users.push(user);
+        assert!(config.inbound[0].users().iter().all(|u| u.name != "bob"));
+        assert!(rotate_user(&mut config, "0", "bob").is_err());
+
+        config.inbound[0].protocol = Protocol::Trojan;
+        let user = User {
+            name: "carol".to_string(),
+            uuid: uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894"),
+            ..Default::default()
+        };
+        assert!(add_user(&mut config, "0", user).is_err());
+    }
+}
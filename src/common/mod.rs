@@ -1,4 +1,5 @@
 pub mod hash;
+pub mod time;
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use tokio::io::{AsyncRead, AsyncReadExt};
@@ -15,6 +16,40 @@ pub const KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV: &[u8] = b"AEAD Resp Header Len
 pub const KDFSALT_CONST_AEAD_RESP_HEADER_KEY: &[u8] = b"AEAD Resp Header Key";
 pub const KDFSALT_CONST_AEAD_RESP_HEADER_IV: &[u8] = b"AEAD Resp Header IV";
 
+/// logs to the console of the worker, or to stderr when running natively
+#[macro_export]
+macro_rules! log {
+    ( $($t:tt)* ) => {
+        {
+            #[cfg(target_arch = "wasm32")]
+            worker::console_log!($($t)*);
+            #[cfg(not(target_arch = "wasm32"))]
+            eprintln!($($t)*);
+        }
+    }
+}
+
+/// compares two secrets in constant time to avoid leaking them through timing
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    let diff = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    a.len() == b.len() && diff == 0
+}
+
+/// runs a future in the background on the current thread
+#[cfg(target_arch = "wasm32")]
+pub fn spawn(fut: impl std::future::Future<Output = ()> + 'static) {
+    worker::wasm_bindgen_futures::spawn_local(fut);
+}
+
+/// runs a future in the background on the current thread, needs a `LocalSet`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn(fut: impl std::future::Future<Output = ()> + 'static) {
+    tokio::task::spawn_local(fut);
+}
+
 #[macro_export]
 macro_rules! md5 {
     ( $($v:expr),+ ) => {
@@ -80,17 +115,7 @@ pub async fn parse_ipv4<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Resul
 pub async fn parse_ipv6<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
     let mut addr = [0u8; 16];
     buf.read_exact(&mut addr).await?;
-    Ok(Ipv6Addr::new(
-        ((addr[0] as u16) << 16) | (addr[1] as u16),
-        ((addr[2] as u16) << 16) | (addr[3] as u16),
-        ((addr[4] as u16) << 16) | (addr[5] as u16),
-        ((addr[6] as u16) << 16) | (addr[7] as u16),
-        ((addr[8] as u16) << 16) | (addr[9] as u16),
-        ((addr[10] as u16) << 16) | (addr[11] as u16),
-        ((addr[12] as u16) << 16) | (addr[13] as u16),
-        ((addr[14] as u16) << 16) | (addr[15] as u16),
-    )
-    .to_string())
+    Ok(Ipv6Addr::from(addr).to_string())
 }
 
 pub async fn parse_domain<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
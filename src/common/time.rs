@@ -0,0 +1,160 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+/// current unix time in milliseconds
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> u64 {
+    worker::Date::now().as_millis()
+}
+
+/// current unix time in milliseconds
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// waits for the given duration using the timer of the runtime
+#[cfg(target_arch = "wasm32")]
+pub fn sleep(duration: Duration) -> Sleep {
+    Box::pin(worker::Delay::from(duration))
+}
+
+/// waits for the given duration using the timer of the runtime
+#[cfg(not(target_arch = "wasm32"))]
+pub fn sleep(duration: Duration) -> Sleep {
+    Box::pin(tokio::time::sleep(duration))
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+/// returns the (year, month, day) of a unix timestamp in milliseconds
+pub fn date(millis: u64) -> (i64, u32, u32) {
+    civil_from_days(millis as i64 / MILLIS_PER_DAY)
+}
+
+/// returns the minutes passed since midnight (UTC) of a unix timestamp in milliseconds
+pub fn minute_of_day(millis: u64) -> u32 {
+    ((millis as i64 % MILLIS_PER_DAY) / 60_000) as u32
+}
+
+/// parses `HH:MM` into the minutes passed since midnight
+pub fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    if h > 24 || m > 59 || (h == 24 && m > 0) {
+        return None;
+    }
+
+    Some(h * 60 + m)
+}
+
+/// parses an RFC 3339 date-time (E.g. 2024-05-01T12:00:00Z) or a plain date
+/// (E.g. 2024-05-01) into a unix timestamp in milliseconds
+pub fn parse_datetime(s: &str) -> Option<u64> {
+    let (date, time) = match s.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let mut parts = date.splitn(3, '-');
+    let year = parts.next()?.parse::<i64>().ok()?;
+    let month = parts.next()?.parse::<u32>().ok()?;
+    let day = parts.next()?.parse::<u32>().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut millis = days_from_civil(year, month, day) * MILLIS_PER_DAY;
+
+    if let Some(time) = time {
+        let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+            Some(i) => time.split_at(i),
+            None => return None,
+        };
+
+        let mut parts = time.splitn(3, ':');
+        let hour = parts.next()?.parse::<i64>().ok()?;
+        let minute = parts.next()?.parse::<i64>().ok()?;
+        let second = parts
+            .next()
+            .map(|s| s.split('.').next().unwrap_or(s).parse::<i64>())
+            .unwrap_or(Ok(0))
+            .ok()?;
+        if hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        millis += ((hour * 60 + minute) * 60 + second) * 1000;
+
+        let offset = match offset {
+            "Z" | "z" => 0,
+            _ => {
+                let sign = if offset.starts_with('-') { -1 } else { 1 };
+                let minutes = parse_time(&offset[1..])? as i64;
+                sign * minutes * 60_000
+            }
+        };
+        millis -= offset;
+    }
+
+    u64::try_from(millis).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_datetime() {
+        assert_eq!(parse_datetime("1970-01-01"), Some(0));
+        assert_eq!(parse_datetime("2024-03-01"), Some(1_709_251_200_000));
+        assert_eq!(
+            parse_datetime("2024-02-29T23:59:59Z"),
+            Some(1_709_251_199_000)
+        );
+        assert_eq!(
+            parse_datetime("2024-03-01T03:30:00+03:30"),
+            Some(1_709_251_200_000)
+        );
+        assert_eq!(parse_datetime("2024-02-29T23:59:59"), None);
+        assert_eq!(parse_datetime("2024-13-01"), None);
+        assert_eq!(parse_datetime("yesterday"), None);
+
+        assert_eq!(date(1_709_251_199_000), (2024, 2, 29));
+        assert_eq!(minute_of_day(1_709_251_199_000), 23 * 60 + 59);
+        assert_eq!(parse_time("09:30"), Some(570));
+        assert_eq!(parse_time("25:00"), None);
+    }
+}
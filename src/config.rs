@@ -1,6 +1,8 @@
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 
-use crate::proxy::{Network, RequestContext};
+use crate::common::time;
+use crate::proxy::{balancer, Network, RequestContext};
 
 use cidr::IpCidr;
 use schemars::JsonSchema;
@@ -19,10 +21,103 @@ pub enum Protocol {
     RelayV2,
     Blackhole,
     Freedom,
+    Shadowsocks,
+}
+
+impl Protocol {
+    /// whether tunl can connect through an outbound of the protocol, the
+    /// others are only served as inbounds
+    pub fn has_outbound(&self) -> bool {
+        matches!(
+            self,
+            Protocol::Vless
+                | Protocol::Trojan
+                | Protocol::RelayV1
+                | Protocol::RelayV2
+                | Protocol::Blackhole
+                | Protocol::Freedom
+        )
+    }
+
+    /// the credential the clients of an inbound authenticate with, `None`
+    /// for the protocols without one
+    pub fn credential(&self) -> Option<&'static str> {
+        match self {
+            Protocol::Vmess | Protocol::Vless => Some("uuid"),
+            Protocol::Trojan => Some("password"),
+            _ => None,
+        }
+    }
+}
+
+/// How the connection to an outbound is secured
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SecureTransport {
+    #[default]
+    Off,
+    On,
+    /// The outbound handshake is sent in the clear, the rest over TLS
+    #[serde(rename = "starttls")]
+    StartTls,
+}
+
+/// Order in which the addresses of an outbound are tried
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    Random,
+    RoundRobin,
+    /// The same client keeps using the same address
+    StickyClient,
+    /// The same destination is always reached through the same address
+    StickyDestination,
+}
+
+/// What carries the outbound protocol to the addresses
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// A websocket opened with an http upgrade, over TLS if secure_transport is on
+    Ws,
+}
+
+/// Request of the websocket transport
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct WsTransport {
+    /// # Path of the upgrade request (E.g. /ws)
+    pub path: String,
+    /// # Host header, defaults to the address
+    pub host: Option<String>,
+    /// # Additional headers of the upgrade request
+    pub headers: BTreeMap<String, String>,
+    /// # Leading bytes sent along with the upgrade request (0 disables early data)
+    pub early_data: usize,
+    /// # Header carrying the early data in base64url
+    pub early_data_header: String,
+}
+
+impl Default for WsTransport {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            host: None,
+            headers: BTreeMap::new(),
+            early_data: 0,
+            early_data_header: "Sec-WebSocket-Protocol".to_string(),
+        }
+    }
 }
 
 #[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Outbound {
+    /// # Name routing rules and balancers refer to
+    #[serde(default)]
+    pub tag: String,
     #[schemars(with = "Vec<IpAddr>")]
     /// # List of Ip Rages (E.g. 103.22.200.0/22)
     pub r#match: Vec<IpCidr>,
@@ -34,6 +129,246 @@ pub struct Outbound {
     pub port: u16,
     #[serde(default)]
     pub uuid: Uuid,
+    // only for trojan/shadowsocks
+    #[serde(default)]
+    pub password: String,
+    /// # Shadowsocks cipher (E.g. aes-256-gcm)
+    #[serde(default)]
+    pub method: String,
+    /// # Selection of the address to connect to, the others are tried if it fails
+    #[serde(default)]
+    pub strategy: Strategy,
+    /// # Seconds a failed address is skipped for, defaults to 30
+    #[serde(default)]
+    pub cooldown: Option<u64>,
+    /// # Transport security of the connection to the addresses (off, on or starttls)
+    #[serde(default)]
+    pub secure_transport: SecureTransport,
+    /// tls server name, workers only take it from the address so anything
+    /// else is reported by `validate`. left out of the schema until it can
+    /// be honoured
+    #[serde(default)]
+    #[schemars(skip)]
+    pub sni: Option<String>,
+    /// # Transport of the connection to the addresses (tcp or ws)
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub ws: WsTransport,
+    /// # Tag of the outbound this one connects through (E.g. a relay in front of a vless server)
+    #[serde(default)]
+    pub via: Option<String>,
+    /// the outbound named by `via`, filled in when dispatching
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub detour: Option<Box<Outbound>>,
+}
+
+impl Outbound {
+    /// builds an outbound from a share link, all the traffic is routed through it
+    pub fn from_link(link: &str) -> Result<Self, String> {
+        let mut outbound = crate::import::parse_link(link)?;
+        outbound.r#match = vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        Ok(outbound)
+    }
+}
+
+/// How a balancer orders its outbounds, the others are tried if the first fails
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    Random,
+    RoundRobin,
+    /// The outbound with the fewest open connections of the isolate
+    LeastConnections,
+    /// The outbound with the lowest observed connect latency
+    LeastLatency,
+}
+
+/// Group of outbounds sharing the traffic routed to it
+#[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Balancer {
+    /// # Name routing rules refer to
+    pub tag: String,
+    /// # Tags of the outbounds in the group
+    pub outbounds: Vec<String>,
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    /// # Target the outbounds are probed with to measure their latency (E.g. 1.1.1.1:443)
+    #[serde(default)]
+    pub probe: Option<String>,
+    /// # Seconds between two probes of an outbound, defaults to 60
+    #[serde(default)]
+    pub probe_interval: Option<u64>,
+}
+
+/// Routes the destinations in the ranges to an outbound or a balancer
+#[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Rule {
+    #[schemars(with = "Vec<IpAddr>")]
+    /// # List of Ip Rages (E.g. 103.22.200.0/22)
+    pub r#match: Vec<IpCidr>,
+    /// # Tag of an outbound or a balancer
+    pub outbound: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum OutboundOrLink {
+    Link(String),
+    Outbound(Outbound),
+}
+
+/// accepts either an outbound table or a share link (E.g. `outbound = "vless://..."`)
+fn deserialize_outbound<'de, D>(deserializer: D) -> Result<Outbound, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match OutboundOrLink::deserialize(deserializer)? {
+        OutboundOrLink::Link(link) => Outbound::from_link(&link).map_err(serde::de::Error::custom),
+        OutboundOrLink::Outbound(outbound) => Ok(outbound),
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct Quota {
+    /// # Traffic limit per calendar month in bytes (0 means unlimited)
+    #[serde(default)]
+    pub monthly: u64,
+    /// # Overall traffic limit in bytes (0 means unlimited)
+    #[serde(default)]
+    pub total: u64,
+}
+
+impl Quota {
+    pub fn is_limited(&self) -> bool {
+        self.monthly > 0 || self.total > 0
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+pub struct Limits {
+    /// # Maximum number of concurrent connections (0 means unlimited)
+    #[serde(default)]
+    pub max_connections: u32,
+    /// # Maximum number of new connections per minute (0 means unlimited)
+    #[serde(default)]
+    pub connections_per_minute: u32,
+    /// # Maximum throughput of each connection in bytes per second (0 means unlimited)
+    #[serde(default)]
+    pub bandwidth: u64,
+}
+
+impl Limits {
+    /// whether the connection counters have to be checked
+    pub fn is_limited(&self) -> bool {
+        self.max_connections > 0 || self.connections_per_minute > 0
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TimeWindow {
+    /// # Start of the window in UTC (E.g. 09:00)
+    pub start: String,
+    /// # End of the window in UTC (E.g. 17:30)
+    pub end: String,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, minute: u32) -> bool {
+        match (time::parse_time(&self.start), time::parse_time(&self.end)) {
+            (Some(start), Some(end)) if start <= end => start <= minute && minute < end,
+            // windows like 22:00-06:00 wrap around midnight
+            (Some(start), Some(end)) => minute >= start || minute < end,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct User {
+    pub name: String,
+    // only for vmess/vless
+    #[serde(default)]
+    pub uuid: Uuid,
+    // only for trojan
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub disabled: bool,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub limits: Limits,
+    /// # Secret token of the user's subscription (E.g. /sub/{token})
+    #[serde(default)]
+    pub sub_token: String,
+    /// # Date-time the user becomes valid (E.g. 2024-05-01T00:00:00Z)
+    #[serde(default)]
+    pub not_before: Option<String>,
+    /// # Date-time the user expires (E.g. 2024-06-01T00:00:00Z)
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    /// # Times of day the user is allowed to connect, empty means any time
+    #[serde(default)]
+    pub windows: Vec<TimeWindow>,
+}
+
+impl User {
+    pub fn is_expired(&self, now: u64) -> bool {
+        match &self.expires_at {
+            // an unparsable date shouldn't grant access forever
+            Some(expires_at) => time::parse_datetime(expires_at).map_or(true, |t| t <= now),
+            None => false,
+        }
+    }
+
+    /// checks the validity period and time windows of the user
+    pub fn is_active(&self, now: u64) -> bool {
+        if self.disabled || self.is_expired(now) {
+            return false;
+        }
+
+        if let Some(not_before) = &self.not_before {
+            if time::parse_datetime(not_before).map_or(true, |t| now < t) {
+                return false;
+            }
+        }
+
+        let minute = time::minute_of_day(now);
+        self.windows.is_empty() || self.windows.iter().any(|w| w.contains(minute))
+    }
+}
+
+/// How clients reach an inbound
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InboundTransport {
+    #[default]
+    Ws,
+    /// Streaming GET for the download and sequenced POSTs for the upload
+    /// (E.g. xray's SplitHTTP), needs the `SESSIONS` durable object binding
+    #[serde(rename = "splithttp")]
+    SplitHttp,
+    /// The gun service of v2ray served at /{path without the leading slash}/Tun
+    Grpc,
+    /// A raw stream after a plain HTTP upgrade, workers can only complete the
+    /// upgrade as a websocket so the raw stream is only served by the native server
+    #[serde(rename = "httpupgrade")]
+    HttpUpgrade,
+}
+
+impl InboundTransport {
+    /// name of the transport in share links and client configs
+    pub fn network(&self) -> &'static str {
+        match self {
+            Self::Ws => "ws",
+            Self::SplitHttp => "splithttp",
+            Self::Grpc => "grpc",
+            Self::HttpUpgrade => "httpupgrade",
+        }
+    }
 }
 
 #[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
@@ -46,12 +381,213 @@ pub struct Inbound {
     #[serde(default)]
     pub password: String,
     pub path: String,
+    /// # Transport of the clients (ws, splithttp, grpc or httpupgrade)
+    #[serde(default)]
+    pub transport: InboundTransport,
+    // subscription token of the default user
+    #[serde(default)]
+    pub sub_token: String,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+impl Inbound {
+    /// name of the user built from the top level `uuid`/`password` fields
+    pub const DEFAULT_USER: &'static str = "default";
+
+    /// name of the grpc service, taken from the path
+    pub fn service_name(&self) -> &str {
+        self.path.trim_matches('/')
+    }
+
+    /// whether the user has the credential of the protocol. a nil uuid or an
+    /// empty password would be accepted from anyone
+    pub fn has_credential(&self, user: &User) -> bool {
+        match self.protocol.credential() {
+            Some("uuid") => !user.uuid.is_nil(),
+            Some(_) => !user.password.is_empty(),
+            None => true,
+        }
+    }
+
+    /// the user built from the top level fields, if any of them is set
+    fn default_user(&self) -> Option<User> {
+        if self.uuid.is_nil() && self.password.is_empty() {
+            return None;
+        }
+        Some(User {
+            name: Self::DEFAULT_USER.to_string(),
+            uuid: self.uuid,
+            password: self.password.clone(),
+            sub_token: self.sub_token.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// returns the enabled users of the inbound, including the default one.
+    /// users without the credential of the protocol are left out
+    pub fn users(&self) -> Vec<User> {
+        let users = self.users.iter().filter(|u| !u.disabled).cloned();
+        self.default_user()
+            .into_iter()
+            .chain(users)
+            .filter(|u| self.has_credential(u))
+            .collect()
+    }
+}
+
+/// Admin API, authenticated with the `ADMIN_TOKEN` secret and persisting
+/// changes to the `TUNL` kv namespace
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Admin {
+    /// # Path prefix of the admin API (E.g. /admin)
+    pub path: String,
+}
+
+/// Subscription endpoint serving the links of the users owning a token
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Subscription {
+    /// # Path prefix of the subscriptions (E.g. /sub)
+    pub path: String,
+}
+
+/// Settings of the generated share links
+#[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LinkOptions {
+    /// # Front addresses or clean IPs clients connect to, defaults to the worker host
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// # Ports clients connect to (E.g. 443, 8443, 2053), defaults to 443
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// # TLS server name, defaults to the worker host
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// # Host header of the websocket, defaults to the worker host
+    #[serde(default)]
+    pub host: Option<String>,
+    /// # TLS client fingerprint (E.g. chrome)
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// # TLS application protocols (E.g. http/1.1)
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// # Remark template, supports {user}, {protocol}, {address} and {port}
+    #[serde(default)]
+    pub remark: Option<String>,
+}
+
+/// What to do with text frames, the protocols only use binary ones
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TextPolicy {
+    /// Close the connection with code 1003
+    #[default]
+    Reject,
+    /// Treat the text as its UTF-8 bytes
+    Bytes,
+}
+
+/// Flow control and framing of the websocket streams
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct WebSocketOptions {
+    /// # Largest payload of a single frame in bytes (0 means unlimited)
+    pub max_frame_size: usize,
+    /// # Buffered bytes after which writes wait for the socket to drain (0 means unlimited)
+    ///
+    /// it's checked against `bufferedAmount`, which workerd always reports as
+    /// zero, and workerd has no other drain signal. on workers it has no effect
+    /// and the send queue to a slow client isn't bounded. the native server
+    /// waits on its socket instead
+    pub high_water_mark: usize,
+    /// # Handling of text frames (reject or bytes)
+    pub text: TextPolicy,
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 64 * 1024,
+            high_water_mark: 1024 * 1024,
+            text: TextPolicy::Reject,
+        }
+    }
+}
+
+/// Limits of the uploads of the splithttp sessions
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct SplitHttpOptions {
+    /// # Largest body of a single upload request in bytes
+    pub max_post_size: usize,
+    /// # Upload requests which may arrive ahead of their turn
+    pub max_buffered_posts: usize,
+}
+
+impl Default for SplitHttpOptions {
+    fn default() -> Self {
+        Self {
+            max_post_size: 1_000_000,
+            max_buffered_posts: 30,
+        }
+    }
+}
+
+/// Timeouts of the connections in seconds, 0 disables them
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct Timeouts {
+    /// # Seconds a client has to send its request header
+    pub handshake: u64,
+    /// # Seconds to connect to the upstream and finish the outbound handshake
+    pub connect: u64,
+    /// # Seconds a tunnel may go without any traffic
+    pub idle: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: 10,
+            connect: 10,
+            idle: 300,
+        }
+    }
 }
 
+// longest chain of outbounds, also stops `via` loops
+const MAX_HOPS: usize = 8;
+
 #[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Config {
     pub inbound: Vec<Inbound>,
+    /// # Outbound table or a vless:// or trojan:// share link
+    #[serde(deserialize_with = "deserialize_outbound")]
+    #[schemars(with = "OutboundOrLink")]
     pub outbound: Outbound,
+    /// # Additional outbounds, used through routing rules and balancers
+    #[serde(default)]
+    pub outbounds: Vec<Outbound>,
+    #[serde(default)]
+    pub balancer: Vec<Balancer>,
+    /// # Rules checked before the match of the main outbound
+    #[serde(default)]
+    pub routing: Vec<Rule>,
+    #[serde(default)]
+    pub admin: Option<Admin>,
+    #[serde(default)]
+    pub subscription: Option<Subscription>,
+    #[serde(default)]
+    pub link: LinkOptions,
+    #[serde(default)]
+    pub websocket: WebSocketOptions,
+    #[serde(default)]
+    pub splithttp: SplitHttpOptions,
+    #[serde(default)]
+    pub timeouts: Timeouts,
 }
 
 impl Config {
@@ -69,29 +605,188 @@ impl Config {
         None
     }
 
-    pub fn dispatch_outbound(&self, context: &RequestContext) -> Outbound {
+    pub fn is_admin(&self, path: &str) -> bool {
+        match &self.admin {
+            Some(admin) => has_prefix(path, &admin.path),
+            None => false,
+        }
+    }
+
+    pub fn is_subscription(&self, path: &str) -> bool {
+        match &self.subscription {
+            Some(subscription) => has_prefix(path, &subscription.path),
+            None => false,
+        }
+    }
+
+    /// reports the settings connections would fail on, each as a readable line
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for inbound in &self.inbound {
+            // workers can only complete an upgrade as a websocket
+            if cfg!(target_arch = "wasm32") && inbound.transport == InboundTransport::HttpUpgrade {
+                problems.push(format!(
+                    "inbound {}: httpupgrade is only served by the native server, not by workers",
+                    inbound.path
+                ));
+            }
+            let users = inbound
+                .default_user()
+                .into_iter()
+                .chain(inbound.users.clone());
+            for user in users.filter(|u| !inbound.has_credential(u)) {
+                problems.push(format!(
+                    "inbound {}: user {} has no {}, it can't connect",
+                    inbound.path,
+                    user.name,
+                    inbound.protocol.credential().unwrap_or_default()
+                ));
+            }
+        }
+
+        let outbounds = std::iter::once(("outbound".to_string(), &self.outbound)).chain(
+            self.outbounds
+                .iter()
+                .enumerate()
+                .map(|(i, outbound)| (format!("outbounds[{i}]"), outbound)),
+        );
+
+        for (name, outbound) in outbounds {
+            let name = match outbound.tag.as_str() {
+                "" => name,
+                tag => format!("{name} ({tag})"),
+            };
+            let mut report = |problem: String| problems.push(format!("{name}: {problem}"));
+
+            if !outbound.protocol.has_outbound() {
+                report(format!(
+                    "{:?} is not supported as outbound",
+                    outbound.protocol
+                ));
+            }
+            if let Err(e) = self.resolve(outbound) {
+                report(e);
+            }
+
+            if outbound.transport == Transport::Ws {
+                if outbound.via.is_some() {
+                    report("the ws transport can't be used through a via hop".to_string());
+                }
+                if outbound.secure_transport == SecureTransport::StartTls {
+                    report("the ws transport can't use starttls".to_string());
+                }
+                if !outbound.ws.path.starts_with('/') {
+                    report(format!("ws path {} has to start with /", outbound.ws.path));
+                }
+            }
+
+            if outbound.secure_transport == SecureTransport::Off {
+                if outbound.sni.is_some() {
+                    report("sni is set but secure_transport is off".to_string());
+                }
+                continue;
+            }
+            match outbound.protocol {
+                Protocol::Vless | Protocol::Trojan | Protocol::RelayV1 | Protocol::RelayV2 => {}
+                ref protocol => report(format!("{protocol:?} outbounds can't use tls")),
+            }
+            if outbound.via.is_some() {
+                report("tls can't be used through a via hop, only on direct sockets".to_string());
+            }
+            // the sockets of workers take the server name from the address
+            if let Some(sni) = &outbound.sni {
+                for address in outbound.addresses.iter().filter(|address| *address != sni) {
+                    report(format!(
+                        "sni {sni} differs from the address {address}, workers can only use the address as server name"
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// returns an outbound by its tag, the main one included
+    pub fn outbound_by_tag(&self, tag: &str) -> Option<&Outbound> {
+        std::iter::once(&self.outbound)
+            .chain(&self.outbounds)
+            .find(|outbound| !outbound.tag.is_empty() && outbound.tag == tag)
+    }
+
+    /// fills in the chain of outbounds named by `via`. a chain which can't be
+    /// followed is an error, dialing without the hop would skip the relay
+    pub fn resolve(&self, outbound: &Outbound) -> std::result::Result<Outbound, String> {
+        let mut outbound = outbound.clone();
+        let mut hop = &mut outbound;
+        for depth in 0.. {
+            let Some(tag) = hop.via.clone() else {
+                break;
+            };
+            if depth == MAX_HOPS {
+                return Err(format!(
+                    "the via chain is longer than {MAX_HOPS} hops or loops"
+                ));
+            }
+            let Some(next) = self.outbound_by_tag(&tag) else {
+                return Err(format!("via refers to the unknown outbound {tag}"));
+            };
+            hop = hop.detour.insert(Box::new(next.clone()));
+        }
+        Ok(outbound)
+    }
+
+    /// returns the outbounds to try in order, a single one unless the
+    /// destination is routed to a balancer
+    pub fn dispatch_outbound(&self, context: &RequestContext) -> worker::Result<Vec<Outbound>> {
+        let resolve = |outbound| self.resolve(outbound).map_err(worker::Error::RustError);
         match &context.network {
             Network::Udp => {
-                return self.outbound.clone();
+                return Ok(vec![resolve(&self.outbound)?]);
             }
             _ => {}
         }
 
         if let Ok(ip) = context.address.clone().parse::<IpAddr>() {
+            let rule = self
+                .routing
+                .iter()
+                .find(|rule| rule.r#match.iter().any(|cidr| cidr.contains(&ip)));
+            if let Some(rule) = rule {
+                if let Some(outbound) = self.outbound_by_tag(&rule.outbound) {
+                    return Ok(vec![resolve(outbound)?]);
+                }
+                if let Some(balancer) = self.balancer.iter().find(|b| b.tag == rule.outbound) {
+                    let members = balancer
+                        .outbounds
+                        .iter()
+                        .filter_map(|tag| self.outbound_by_tag(tag))
+                        .map(resolve)
+                        .collect::<worker::Result<Vec<Outbound>>>()?;
+                    if !members.is_empty() {
+                        return Ok(balancer::order(balancer, members, context, &self.timeouts));
+                    }
+                }
+                crate::log!("[routing] unknown outbound {}", rule.outbound);
+            }
+
             if self.outbound.r#match.iter().any(|cidr| cidr.contains(&ip)) {
-                return self.outbound.clone();
+                return Ok(vec![resolve(&self.outbound)?]);
             }
         }
 
         // freedom outbound with dummy values
         // TODO: change outbound to enum
-        Outbound {
+        Ok(vec![Outbound {
             protocol: Protocol::Freedom,
             ..Default::default()
-        }
+        }])
     }
 }
 
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    path == prefix || path.starts_with(&format!("{prefix}/"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,4 +841,285 @@ mod tests {
             uuid::uuid!("0fbf4f81-2598-4b6a-a623-0ead4cb9efa8")
         );
     }
+
+    #[test]
+    fn test_outbound_link() {
+        let config = Config::new(
+            r#"
+            outbound = "trojan://secret@relay.example.com:8443?security=tls#relay"
+
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+            "#,
+        );
+
+        assert_eq!(config.inbound.len(), 1);
+        assert_eq!(config.outbound.protocol, Protocol::Trojan);
+        assert_eq!(config.outbound.addresses, vec!["relay.example.com"]);
+        assert_eq!(config.outbound.port, 8443);
+        assert_eq!(config.outbound.password, "secret");
+        assert!(config
+            .outbound
+            .r#match
+            .iter()
+            .any(|cidr| cidr.contains(&"8.8.8.8".parse::<IpAddr>().unwrap())));
+    }
+
+    #[test]
+    fn test_users() {
+        let buf = r#"
+            [admin]
+            path = "/admin"
+
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+            users = [
+                { name = "alice", uuid = "96850032-1b92-46e9-a4f2-b99631456894", quota = { monthly = 1024 } },
+                { name = "bob", uuid = "c48619fe-8f02-49e0-b9e9-edf763e17e21", disabled = true },
+            ]
+
+            [[inbound]]
+            protocol = "trojan"
+            path = "/trojan"
+            users = [{ name = "carol", password = "secret" }]
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+        "#;
+        let config = Config::new(buf);
+
+        let users = config.inbound[0].users();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].name, Inbound::DEFAULT_USER);
+        assert_eq!(users[1].name, "alice");
+        assert_eq!(users[1].quota.monthly, 1024);
+        assert!(users[1].quota.is_limited());
+        assert!(!users[0].quota.is_limited());
+
+        let users = config.inbound[1].users();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].password, "secret");
+
+        assert!(config.is_admin("/admin"));
+        assert!(config.is_admin("/admin/users"));
+        assert!(!config.is_admin("/administrator"));
+        assert!(!config.is_admin("/vless"));
+    }
+
+    #[test]
+    fn test_user_validity() {
+        let user = User {
+            name: "contractor".to_string(),
+            not_before: Some("2024-05-01T00:00:00Z".to_string()),
+            expires_at: Some("2024-06-01".to_string()),
+            windows: vec![
+                TimeWindow {
+                    start: "09:00".to_string(),
+                    end: "17:00".to_string(),
+                },
+                TimeWindow {
+                    start: "22:00".to_string(),
+                    end: "01:00".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        // 2024-05-10T10:00:00Z
+        let now = 1_715_335_200_000;
+        assert!(user.is_active(now));
+        // 2024-05-10T18:00:00Z
+        assert!(!user.is_active(now + 8 * 3_600_000));
+        // 2024-05-10T23:00:00Z
+        assert!(user.is_active(now + 13 * 3_600_000));
+        // 2024-04-30T10:00:00Z
+        assert!(!user.is_active(now - 10 * 86_400_000));
+        // 2024-06-01T10:00:00Z
+        assert!(user.is_expired(now + 22 * 86_400_000));
+        assert!(!user.is_active(now + 22 * 86_400_000));
+
+        let user = User {
+            expires_at: Some("soon".to_string()),
+            ..Default::default()
+        };
+        assert!(user.is_expired(0));
+    }
+
+    #[test]
+    fn test_dispatch_outbound() {
+        let config = Config::new(
+            r#"
+            inbound = []
+
+            [outbound]
+            tag = "direct-relay"
+            protocol = "relay_v2"
+            addresses = ["10.0.0.1"]
+            match = ["1.1.1.0/24"]
+
+            [[outbounds]]
+            tag = "upstream"
+            protocol = "vless"
+            addresses = ["10.0.0.2"]
+            match = []
+
+            [[balancer]]
+            tag = "all"
+            outbounds = ["direct-relay", "upstream", "missing"]
+            strategy = "round_robin"
+
+            [[routing]]
+            match = ["8.8.8.0/24"]
+            outbound = "all"
+
+            [[routing]]
+            match = ["9.9.9.0/24"]
+            outbound = "upstream"
+            "#,
+        );
+        let tags = |address: &str| -> Vec<String> {
+            let context = RequestContext {
+                address: address.to_string(),
+                ..Default::default()
+            };
+            config
+                .dispatch_outbound(&context)
+                .unwrap()
+                .into_iter()
+                .map(|outbound| format!("{}{:?}", outbound.tag, outbound.protocol))
+                .collect()
+        };
+
+        assert_eq!(tags("1.1.1.1"), ["direct-relayRelayV2"]);
+        assert_eq!(tags("9.9.9.9"), ["upstreamVless"]);
+        assert_eq!(tags("8.8.8.8"), ["direct-relayRelayV2", "upstreamVless"]);
+        assert_eq!(tags("8.8.8.8"), ["upstreamVless", "direct-relayRelayV2"]);
+        assert_eq!(tags("4.4.4.4"), ["Freedom"]);
+    }
+
+    #[test]
+    fn test_broken_chain() {
+        let config = Config::new(
+            r#"
+            inbound = []
+
+            [outbound]
+            tag = "upstream"
+            protocol = "vless"
+            addresses = ["10.0.0.1"]
+            via = "missing"
+            match = ["1.1.1.0/24"]
+
+            [[outbounds]]
+            tag = "a"
+            protocol = "relay_v2"
+            addresses = ["10.0.0.2"]
+            via = "b"
+            match = []
+
+            [[outbounds]]
+            tag = "b"
+            protocol = "relay_v2"
+            addresses = ["10.0.0.3"]
+            via = "a"
+            match = []
+
+            [[routing]]
+            match = ["8.8.8.0/24"]
+            outbound = "a"
+            "#,
+        );
+        let dispatch = |address: &str| {
+            let context = RequestContext {
+                address: address.to_string(),
+                ..Default::default()
+            };
+            config.dispatch_outbound(&context)
+        };
+
+        // the connection fails instead of dialing without the hop
+        assert!(dispatch("1.1.1.1").is_err());
+        assert!(dispatch("8.8.8.8").is_err());
+        assert_eq!(
+            config.validate(),
+            [
+                "outbound (upstream): via refers to the unknown outbound missing",
+                "outbounds[0] (a): the via chain is longer than 8 hops or loops",
+                "outbounds[1] (b): the via chain is longer than 8 hops or loops",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            password = "secret"
+            path = "/vless"
+            users = [{ name = "alice", uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8" }]
+
+            [[inbound]]
+            protocol = "trojan"
+            path = "/trojan"
+            users = [{ name = "bob", uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8" }]
+
+            [outbound]
+            protocol = "vless"
+            addresses = ["vless.example.com"]
+            secure_transport = "on"
+            sni = "vless.example.com"
+            match = []
+
+            [[outbounds]]
+            tag = "direct"
+            protocol = "freedom"
+            secure_transport = "on"
+            match = []
+
+            [[outbounds]]
+            protocol = "trojan"
+            addresses = ["1.2.3.4"]
+            secure_transport = "starttls"
+            sni = "trojan.example.com"
+            via = "relay"
+            match = []
+
+            [[outbounds]]
+            tag = "cdn"
+            protocol = "vless"
+            addresses = ["cdn.example.com"]
+            transport = "ws"
+            secure_transport = "starttls"
+            ws = { path = "ws" }
+            match = []
+
+            [[outbounds]]
+            protocol = "shadowsocks"
+            addresses = ["ss.example.com"]
+            match = []
+            "#,
+        );
+
+        assert_eq!(
+            config.validate(),
+            [
+                "inbound /vless: user default has no uuid, it can't connect",
+                "inbound /trojan: user bob has no password, it can't connect",
+                "outbounds[0] (direct): Freedom outbounds can't use tls",
+                "outbounds[1]: via refers to the unknown outbound relay",
+                "outbounds[1]: tls can't be used through a via hop, only on direct sockets",
+                "outbounds[1]: sni trojan.example.com differs from the address 1.2.3.4, workers can only use the address as server name",
+                "outbounds[2] (cdn): the ws transport can't use starttls",
+                "outbounds[2] (cdn): ws path ws has to start with /",
+                "outbounds[3]: Shadowsocks is not supported as outbound",
+            ]
+        );
+    }
 }
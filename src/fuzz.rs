@@ -0,0 +1,92 @@
+//! entry points of the cargo-fuzz targets in `fuzz/`, each one feeds untrusted
+//! bytes to a header decoder and must never panic
+
+use crate::config::User;
+use crate::proxy::{bepass, relay, trojan, vless, vmess};
+
+use std::future::Future;
+use std::io::Cursor;
+
+use worker::Url;
+
+fn users() -> Vec<User> {
+    vec![User {
+        name: "fuzz".to_string(),
+        uuid: "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8".parse().unwrap(),
+        password: "password".to_string(),
+        ..Default::default()
+    }]
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+pub fn vless(data: &[u8]) {
+    let mut stream = Cursor::new(data.to_vec());
+    let _ = block_on(vless::encoding::decode_request_header(
+        &mut stream,
+        &users(),
+    ));
+}
+
+pub fn vmess(data: &[u8]) {
+    let mut stream = Cursor::new(data.to_vec());
+    let _ = block_on(vmess::encoding::decode_request_header(
+        &mut stream,
+        &users(),
+    ));
+}
+
+pub fn trojan(data: &[u8]) {
+    let mut stream = Cursor::new(data.to_vec());
+    let _ = block_on(trojan::encoding::decode_request_header(
+        &mut stream,
+        &users(),
+    ));
+}
+
+/// the input is used as the query string of the websocket url
+pub fn bepass(data: &[u8]) {
+    let query = String::from_utf8_lossy(data);
+    if let Ok(url) = Url::parse(&format!("https://tunl.workers.dev/bepass?{query}")) {
+        let _ = bepass::encoding::decode_request_header(&url);
+    }
+}
+
+pub fn relay(data: &[u8]) {
+    let _ = relay::outbound::decode_header(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// replays the seed corpus, the fuzzers themselves need a nightly toolchain
+    #[test]
+    fn test_seed_corpus() {
+        let targets = [
+            ("vless", vless as fn(&[u8])),
+            ("vmess", vmess),
+            ("trojan", trojan),
+            ("bepass", bepass),
+            ("relay", relay),
+        ];
+
+        for (name, target) in targets {
+            let dir = format!("{}/fuzz/corpus/{name}", env!("CARGO_MANIFEST_DIR"));
+            let seeds = std::fs::read_dir(&dir).unwrap();
+            for seed in seeds {
+                let data = std::fs::read(seed.unwrap().path()).unwrap();
+                target(&data);
+                // every truncation of a seed is an interesting input too
+                for end in 0..data.len() {
+                    target(&data[..end]);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::{Outbound, Protocol, SecureTransport, Transport, WsTransport};
+use crate::link::decode_component;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::Value;
+use uuid::Uuid;
+use worker::Url;
+
+type Result<T> = std::result::Result<T, String>;
+
+/// parses a vless:// or trojan:// share link into an outbound, the `match`
+/// list is left empty for the caller to fill. vmess:// and ss:// links are
+/// understood but rejected, tunl can't connect through them
+pub fn parse_link(link: &str) -> Result<Outbound> {
+    let link = link.trim();
+    let (scheme, rest) = link
+        .split_once("://")
+        .ok_or_else(|| format!("invalid share link: {link}"))?;
+
+    let outbound = match scheme.to_lowercase().as_str() {
+        "vless" => parse_vless(link),
+        "vmess" => parse_vmess(rest),
+        "trojan" => parse_trojan(link),
+        "ss" => parse_shadowsocks(rest),
+        _ => Err(format!("unsupported share link scheme: {scheme}")),
+    }?;
+    match outbound.protocol.has_outbound() {
+        true => Ok(outbound),
+        false => Err(format!(
+            "unsupported outbound protocol: {:?}",
+            outbound.protocol
+        )),
+    }
+}
+
+/// decodes both the standard and url safe alphabets, with or without padding
+fn decode_base64(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim();
+    [
+        general_purpose::STANDARD,
+        general_purpose::STANDARD_NO_PAD,
+        general_purpose::URL_SAFE,
+        general_purpose::URL_SAFE_NO_PAD,
+    ]
+    .iter()
+    .find_map(|engine| engine.decode(s).ok())
+    .ok_or_else(|| "invalid base64".to_string())
+}
+
+fn parse_url(link: &str) -> Result<(Url, String, u16)> {
+    let url = Url::parse(link).map_err(|e| format!("invalid share link: {e}"))?;
+    let host = url
+        .host_str()
+        .map(|h| h.trim_start_matches('[').trim_end_matches(']').to_string())
+        .ok_or("share link has no address")?;
+    let port = url.port().unwrap_or(443);
+
+    Ok((url, host, port))
+}
+
+/// reads the `security` and `sni` parameters of a share link
+fn parse_tls(url: &Url, default: &str) -> (SecureTransport, Option<String>) {
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let secure_transport = match param("security").as_deref().unwrap_or(default) {
+        "tls" => SecureTransport::On,
+        _ => SecureTransport::Off,
+    };
+    (secure_transport, param("sni").filter(|sni| !sni.is_empty()))
+}
+
+/// reads the `type`, `path` and `host` parameters of a share link
+fn parse_transport(url: &Url) -> (Transport, WsTransport) {
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    match param("type").as_deref() {
+        Some("ws") => (Transport::Ws, ws_transport(param("path"), param("host"))),
+        _ => (Transport::Tcp, WsTransport::default()),
+    }
+}
+
+/// the early data size is passed as `ed` in the query of the path (E.g. /ws?ed=2048)
+pub fn ws_transport(path: Option<String>, host: Option<String>) -> WsTransport {
+    let mut ws = WsTransport {
+        host: host.filter(|host| !host.is_empty()),
+        ..Default::default()
+    };
+    let path = path
+        .filter(|path| !path.is_empty())
+        .unwrap_or(ws.path.clone());
+    let (path, query) = path.split_once('?').unwrap_or((&path, ""));
+    let query: Vec<&str> = query
+        .split('&')
+        .filter(|pair| match pair.strip_prefix("ed=") {
+            Some(size) => {
+                ws.early_data = size.parse().unwrap_or_default();
+                false
+            }
+            None => !pair.is_empty(),
+        })
+        .collect();
+
+    ws.path = match query.is_empty() {
+        true => path.to_string(),
+        false => format!("{path}?{}", query.join("&")),
+    };
+    ws
+}
+
+fn parse_uuid(s: &str) -> Result<Uuid> {
+    Uuid::parse_str(s).map_err(|e| format!("invalid uuid {s}: {e}"))
+}
+
+fn parse_vless(link: &str) -> Result<Outbound> {
+    let (url, address, port) = parse_url(link)?;
+    let (secure_transport, sni) = parse_tls(&url, "none");
+    let (transport, ws) = parse_transport(&url);
+    Ok(Outbound {
+        protocol: Protocol::Vless,
+        addresses: vec![address],
+        port,
+        uuid: parse_uuid(&decode_component(url.username()))?,
+        secure_transport,
+        sni,
+        transport,
+        ws,
+        ..Default::default()
+    })
+}
+
+fn parse_trojan(link: &str) -> Result<Outbound> {
+    let (url, address, port) = parse_url(link)?;
+    let password = decode_component(url.username());
+    if password.is_empty() {
+        return Err("trojan link has no password".to_string());
+    }
+
+    // trojan servers always sit behind tls unless told otherwise
+    let (secure_transport, sni) = parse_tls(&url, "tls");
+    let (transport, ws) = parse_transport(&url);
+    Ok(Outbound {
+        protocol: Protocol::Trojan,
+        addresses: vec![address],
+        port,
+        password,
+        secure_transport,
+        sni,
+        transport,
+        ws,
+        ..Default::default()
+    })
+}
+
+/// vmess links carry a base64 encoded json object (v2rayN format)
+fn parse_vmess(rest: &str) -> Result<Outbound> {
+    let payload = rest.split('#').next().unwrap_or_default();
+    let config: Value = serde_json::from_slice(&decode_base64(payload)?)
+        .map_err(|e| format!("invalid vmess link: {e}"))?;
+
+    let field = |name: &str| match &config[name] {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        _ => String::new(),
+    };
+
+    let address = field("add");
+    if address.is_empty() {
+        return Err("vmess link has no address".to_string());
+    }
+    let port = field("port")
+        .parse::<u16>()
+        .map_err(|_| format!("invalid vmess port: {}", field("port")))?;
+
+    Ok(Outbound {
+        protocol: Protocol::Vmess,
+        addresses: vec![address],
+        port,
+        uuid: parse_uuid(&field("id"))?,
+        secure_transport: match field("tls").as_str() {
+            "tls" => SecureTransport::On,
+            _ => SecureTransport::Off,
+        },
+        transport: match field("net").as_str() {
+            "ws" => Transport::Ws,
+            _ => Transport::Tcp,
+        },
+        ws: ws_transport(Some(field("path")), Some(field("host"))),
+        ..Default::default()
+    })
+}
+
+/// supports both SIP002 (ss://userinfo@host:port) and the legacy format
+/// where everything before the remark is base64 encoded
+fn parse_shadowsocks(rest: &str) -> Result<Outbound> {
+    let rest = rest.split('#').next().unwrap_or_default();
+    let rest = rest.split(['?', '/']).next().unwrap_or_default();
+
+    let (userinfo, server) = match rest.rsplit_once('@') {
+        Some((userinfo, server)) => {
+            // the userinfo is either base64 or percent encoded `method:password`
+            let userinfo = match decode_base64(userinfo).map(String::from_utf8) {
+                Ok(Ok(decoded)) if decoded.contains(':') => decoded,
+                _ => decode_component(userinfo),
+            };
+            (userinfo, server.to_string())
+        }
+        None => {
+            let decoded = String::from_utf8(decode_base64(rest)?)
+                .map_err(|_| "invalid shadowsocks link".to_string())?;
+            let (userinfo, server) = decoded.rsplit_once('@').ok_or("invalid shadowsocks link")?;
+            (userinfo.to_string(), server.to_string())
+        }
+    };
+
+    let (method, password) = userinfo
+        .split_once(':')
+        .ok_or("shadowsocks link has no method")?;
+    let (address, port) = server
+        .rsplit_once(':')
+        .ok_or("shadowsocks link has no port")?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid shadowsocks port: {port}"))?;
+
+    Ok(Outbound {
+        protocol: Protocol::Shadowsocks,
+        addresses: vec![address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string()],
+        port,
+        password: password.to_string(),
+        method: method.to_lowercase(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Inbound};
+    use crate::link::shares;
+
+    #[test]
+    fn test_parse_link() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [[inbound]]
+            protocol = "vmess"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vmess"
+
+            [[inbound]]
+            protocol = "trojan"
+            password = "p@ss word"
+            path = "/trojan"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+
+        // the links generated by tunl can be imported back
+        for share in shares(&config, "tunl.workers.dev", |_| true) {
+            let inbound: &Inbound = share.inbound;
+            let link = share.uri().unwrap();
+            let outbound = match inbound.protocol {
+                Protocol::Vmess => {
+                    // vmess is understood, but there is no outbound for it
+                    assert!(parse_link(&link).is_err());
+                    parse_vmess(link.strip_prefix("vmess://").unwrap()).unwrap()
+                }
+                _ => parse_link(&link).unwrap(),
+            };
+            assert_eq!(outbound.protocol, inbound.protocol);
+            assert_eq!(outbound.addresses, vec!["tunl.workers.dev"]);
+            assert_eq!(outbound.port, 443);
+            assert_eq!(outbound.uuid, inbound.uuid);
+            assert_eq!(outbound.password, inbound.password);
+            if inbound.protocol != Protocol::Vmess {
+                assert_eq!(outbound.secure_transport, SecureTransport::On);
+                assert_eq!(outbound.sni.as_deref(), Some("tunl.workers.dev"));
+            }
+            assert_eq!(outbound.transport, Transport::Ws);
+            assert_eq!(outbound.ws.path, inbound.path);
+            assert_eq!(outbound.ws.host.as_deref(), Some("tunl.workers.dev"));
+        }
+
+        assert!(parse_link("ss://YWVzLTI1Ni1nY206dGVzdA@[2001:db8::1]:8388#ss").is_err());
+        let outbound = parse_shadowsocks("YWVzLTI1Ni1nY206dGVzdA@[2001:db8::1]:8388#ss").unwrap();
+        assert_eq!(outbound.protocol, Protocol::Shadowsocks);
+        assert_eq!(outbound.method, "aes-256-gcm");
+        assert_eq!(outbound.password, "test");
+        assert_eq!(outbound.addresses, vec!["2001:db8::1"]);
+        assert_eq!(outbound.port, 8388);
+
+        // legacy format, base64 of aes-128-gcm:test@1.2.3.4:443
+        let outbound = parse_shadowsocks("YWVzLTEyOC1nY206dGVzdEAxLjIuMy40OjQ0Mw==").unwrap();
+        assert_eq!(outbound.method, "aes-128-gcm");
+        assert_eq!(outbound.addresses, vec!["1.2.3.4"]);
+        assert_eq!(outbound.port, 443);
+
+        let outbound =
+            parse_shadowsocks("2022-blake3-aes-128-gcm:a%2Bb%3D@example.com:443").unwrap();
+        assert_eq!(outbound.method, "2022-blake3-aes-128-gcm");
+        assert_eq!(outbound.password, "a+b=");
+
+        assert!(parse_link("vless://not-a-uuid@example.com:443").is_err());
+        assert!(parse_link("socks://example.com:1080").is_err());
+        assert!(parse_link("example.com").is_err());
+    }
+}
@@ -1,57 +1,153 @@
+mod admin;
 mod common;
 mod config;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+mod import;
+mod limit;
 mod link;
 mod proxy;
+mod qr;
+mod splithttp;
+mod subscription;
+mod traffic;
 
+use std::rc::Rc;
 use std::sync::Arc;
 
-use crate::config::Config;
+use crate::config::{Config, Inbound, InboundTransport};
 use crate::link::generate_link;
-use crate::proxy::RequestContext;
+use crate::proxy::{Background, RequestContext};
 
 use worker::*;
 
 lazy_static::lazy_static! {
     static ref CONFIG: Arc<Config> = {
         let c = include_str!(env!("CONFIG_PATH"));
-        Arc::new(Config::new(c))
+        let config = Config::new(c);
+        for problem in config.validate() {
+            console_log!("[config]: {problem}");
+        }
+        Arc::new(config)
     };
 }
 
 #[event(fetch)]
-async fn main(req: Request, _: Env, _: Context) -> Result<Response> {
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    let config = admin::load_config(&env, CONFIG.clone()).await;
+    let ctx = Rc::new(ctx);
+
     match req.path().as_str() {
-        "/link" => link(req, CONFIG.clone()),
-        path => match CONFIG.dispatch_inbound(path) {
-            Some(inbound) => {
-                let context = RequestContext {
-                    inbound,
-                    request: Some(req),
-                    ..Default::default()
-                };
-                tunnel(CONFIG.clone(), context).await
+        "/link" => link(req, config),
+        path if config.is_admin(path) => admin::handle(req, env, config).await,
+        path if config.is_subscription(path) => subscription::handle(req, &env, config).await,
+        path => match config.dispatch_inbound(path) {
+            Some(inbound) if inbound.transport == InboundTransport::Ws => {
+                let context = context(&req, &env, &ctx, inbound)?;
+                tunnel(config, context).await
+            }
+            // workers can't hand a raw stream to the client after the upgrade
+            Some(inbound) if inbound.transport == InboundTransport::HttpUpgrade => {
+                Response::error("httpupgrade is only served by the native server", 501)
             }
-            None => Response::empty(),
+            _ => match proxy::grpc::dispatch(&config, path) {
+                Some(inbound) => {
+                    let context = context(&req, &env, &ctx, inbound)?;
+                    grpc(config, req, context).await
+                }
+                None => match splithttp::dispatch(&config, path) {
+                    Some((inbound, session, _)) => {
+                        splithttp::forward(req, &env, &inbound, &session).await
+                    }
+                    None => Response::empty(),
+                },
+            },
         },
     }
 }
 
+fn context(
+    req: &Request,
+    env: &Env,
+    ctx: &Rc<Context>,
+    inbound: Inbound,
+) -> Result<RequestContext> {
+    let ctx = ctx.clone();
+    Ok(RequestContext {
+        inbound,
+        client: req.headers().get("CF-Connecting-IP")?.unwrap_or_default(),
+        traffic: traffic::Traffic::new(env),
+        limiter: limit::Limiter::new(env),
+        background: Background::new(move |future| ctx.wait_until(future)),
+        url: req.url().ok(),
+        ..Default::default()
+    })
+}
+
 async fn tunnel(config: Arc<Config>, context: RequestContext) -> Result<Response> {
     let WebSocketPair { server, client } = WebSocketPair::new()?;
 
     server.accept()?;
     wasm_bindgen_futures::spawn_local(async move {
         let events = server.events().unwrap();
+        let stream = proxy::ws::WebSocketStream::new(events, &server, config.websocket);
 
-        if let Err(e) = proxy::process(config, context, &server, events).await {
+        if let Err(e) = proxy::process(config, context, stream).await {
             console_log!("[tunnel]: {}", e);
+            // tell the client why instead of just dropping the socket, it may
+            // have been closed already
+            let (code, reason) = proxy::close::reason(&e);
+            let _ = server.close(Some(code), Some(reason));
         }
     });
 
     Response::from_websocket(client)
 }
 
+/// serves the gun service of a grpc inbound over the streaming bodies of the
+/// call. workers can't send trailers, so the call ends with the body alone
+async fn grpc(config: Arc<Config>, mut req: Request, context: RequestContext) -> Result<Response> {
+    if req.method() != Method::Post {
+        return Response::error("method not allowed", 405);
+    }
+
+    let (stream, body) = proxy::grpc::GrpcStream::new(Box::pin(req.stream()?));
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(e) = proxy::process(config, context, stream).await {
+            console_log!("[grpc]: {}", e);
+        }
+    });
+
+    let mut headers = Headers::new();
+    headers.set("content-type", "application/grpc")?;
+    Ok(Response::from_stream(body)?.with_headers(headers))
+}
+
+/// serves the links as json by default, `?format=html` renders a page of qr codes
+/// and `?format=svg&index=N` the qr code of a single link
 fn link(req: Request, config: Arc<Config>) -> Result<Response> {
-    let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
-    Response::from_json(&generate_link(&config, &host))
+    let url = req.url()?;
+    let host = url.host().map(|x| x.to_string()).unwrap_or_default();
+    let link = generate_link(&config, &host);
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.to_string())
+    };
+
+    let (body, content_type) = match param("format").as_deref() {
+        Some("html") => (qr::html(&link)?, "text/html; charset=utf-8"),
+        Some("svg") => {
+            let index = param("index").and_then(|i| i.parse::<usize>().ok());
+            match index.and_then(|i| link.links.get(i)) {
+                Some(uri) => (qr::svg(uri)?, "image/svg+xml"),
+                None => return Response::error("link not found", 404),
+            }
+        }
+        _ => return Response::from_json(&link),
+    };
+
+    let mut headers = Headers::new();
+    headers.set("content-type", content_type)?;
+    Ok(Response::ok(body)?.with_headers(headers))
 }
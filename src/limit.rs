@@ -0,0 +1,467 @@
+use crate::common::time::{self, Sleep};
+use crate::config::Limits;
+use crate::proxy::{Background, RequestContext};
+use crate::traffic;
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, VecDeque};
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+const DURABLE_OBJECT_BINDING: &str = "LIMITER";
+const CONNECTIONS_KEY: &str = "connections";
+const WINDOW: u64 = 60_000;
+
+// a lease which isn't renewed in time is released on its own, open
+// connections renew theirs at half of it
+const LEASE_TTL: u64 = 2 * 60 * 1000;
+
+pub trait Clock {
+    /// current time in milliseconds
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        time::now()
+    }
+}
+
+/// concurrent and per minute connection counters of a single user or inbound
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct Connections {
+    /// expiry of the leases of the open connections, by id
+    active: BTreeMap<String, u64>,
+    recent: VecDeque<u64>,
+}
+
+impl Connections {
+    pub fn acquire(
+        &mut self,
+        id: &str,
+        limits: &Limits,
+        now: u64,
+    ) -> std::result::Result<(), String> {
+        while let Some(t) = self.recent.front() {
+            if now.saturating_sub(*t) < WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+        // connections which stopped renewing their lease are gone
+        self.active.retain(|_, expires| *expires > now);
+
+        if limits.max_connections > 0 && self.active.len() >= limits.max_connections as usize {
+            return Err(format!(
+                "too many concurrent connections (max {})",
+                limits.max_connections
+            ));
+        }
+
+        if limits.connections_per_minute > 0
+            && self.recent.len() >= limits.connections_per_minute as usize
+        {
+            return Err(format!(
+                "too many new connections (max {} per minute)",
+                limits.connections_per_minute
+            ));
+        }
+
+        self.active.insert(id.to_string(), now + LEASE_TTL);
+        self.recent.push_back(now);
+        Ok(())
+    }
+
+    /// keeps the lease of an open connection from expiring
+    pub fn renew(&mut self, id: &str, now: u64) {
+        if let Some(expires) = self.active.get_mut(id) {
+            *expires = now + LEASE_TTL;
+        }
+    }
+
+    pub fn release(&mut self, id: &str) {
+        self.active.remove(id);
+    }
+}
+
+/// token bucket holding up to one second worth of traffic
+pub struct TokenBucket {
+    rate: u64,
+    tokens: u64,
+    last: u64,
+    // thousandths of a token refilled but not added yet
+    fraction: u64,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+            fraction: 0,
+        }
+    }
+
+    /// takes up to `want` tokens, returns the milliseconds to wait when the bucket is empty
+    pub fn take(&mut self, want: usize, now: u64) -> std::result::Result<usize, u64> {
+        let elapsed = now.saturating_sub(self.last);
+        let refill = elapsed
+            .saturating_mul(self.rate)
+            .saturating_add(self.fraction);
+        self.tokens = self.tokens.saturating_add(refill / 1000).min(self.rate);
+        self.fraction = refill % 1000;
+        self.last = now;
+
+        if self.tokens == 0 {
+            // wait until at least one token is available
+            return Err((1000 / self.rate).max(1));
+        }
+
+        let n = (want as u64).min(self.tokens);
+        self.tokens -= n;
+        Ok(n as usize)
+    }
+
+    /// puts back the tokens which haven't been used
+    pub fn refund(&mut self, n: usize) {
+        self.tokens = self.tokens.saturating_add(n as u64).min(self.rate);
+    }
+}
+
+/// throttles a stream to the given bytes per second
+pub struct Shaper {
+    bucket: TokenBucket,
+    clock: Box<dyn Clock>,
+    sleep: Option<Sleep>,
+}
+
+impl Shaper {
+    pub fn new(rate: u64, clock: Box<dyn Clock>) -> Self {
+        Self {
+            bucket: TokenBucket::new(rate, clock.now()),
+            clock,
+            sleep: None,
+        }
+    }
+
+    /// waits until some bytes can be transferred, returns how many
+    pub fn poll_acquire(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+
+            match self.bucket.take(want, self.clock.now()) {
+                Ok(n) => return Poll::Ready(n),
+                Err(wait) => self.sleep = Some(time::sleep(Duration::from_millis(wait))),
+            }
+        }
+    }
+
+    pub fn refund(&mut self, n: usize) {
+        self.bucket.refund(n);
+    }
+}
+
+/// returns the shaper of a connection based on the strictest bandwidth limit
+pub fn shaper(context: &RequestContext) -> Option<Shaper> {
+    [
+        context.user.limits.bandwidth,
+        context.inbound.limits.bandwidth,
+    ]
+    .into_iter()
+    .filter(|rate| *rate > 0)
+    .min()
+    .map(|rate| Shaper::new(rate, Box::new(SystemClock)))
+}
+
+/// what is asked of a connection counter
+#[derive(Default, Serialize, Deserialize)]
+struct LeaseRequest {
+    /// lease of the connection
+    id: String,
+    #[serde(default)]
+    limits: Limits,
+}
+
+/// client of the connection counters, each counter lives in its own durable object
+#[derive(Clone)]
+pub struct Limiter {
+    namespace: Rc<ObjectNamespace>,
+}
+
+impl Limiter {
+    /// returns `None` if the durable object binding is not configured
+    pub fn new(env: &Env) -> Option<Self> {
+        env.durable_object(DURABLE_OBJECT_BINDING)
+            .ok()
+            .map(|namespace| Self {
+                namespace: Rc::new(namespace),
+            })
+    }
+
+    async fn send(&self, key: &str, action: &str, request: &LeaseRequest) -> Result<Response> {
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(request)?.into()));
+
+        let req = Request::new_with_init(&format!("https://limiter/{action}"), &init)?;
+        let stub = self.namespace.id_from_name(key)?.get_stub()?;
+        stub.fetch_with_request(req).await
+    }
+
+    pub async fn acquire(&self, key: &str, id: &str, limits: &Limits) -> Result<()> {
+        let request = LeaseRequest {
+            id: id.to_string(),
+            limits: *limits,
+        };
+        let mut res = self.send(key, "acquire", &request).await?;
+        match res.status_code() {
+            200 => Ok(()),
+            _ => Err(crate::proxy::close::unauthorized(res.text().await?)),
+        }
+    }
+
+    pub async fn renew(&self, key: &str, id: &str) -> Result<()> {
+        let request = LeaseRequest {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        self.send(key, "renew", &request).await?;
+        Ok(())
+    }
+
+    pub async fn release(&self, key: &str, id: &str) -> Result<()> {
+        let request = LeaseRequest {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        self.send(key, "release", &request).await?;
+        Ok(())
+    }
+}
+
+/// connection slots taken from the limiters, renewed while the connection is
+/// open and released once dropped
+pub struct Lease {
+    limiter: Option<Limiter>,
+    id: String,
+    keys: Vec<String>,
+    open: Rc<Cell<bool>>,
+    background: Background,
+}
+
+impl Lease {
+    pub async fn acquire(context: &RequestContext) -> Result<Self> {
+        let mut lease = Self {
+            limiter: context.limiter.clone(),
+            id: format!("{:016x}", fastrand::u64(..)),
+            keys: Vec::new(),
+            open: Rc::new(Cell::new(true)),
+            background: context.background.clone(),
+        };
+        let limiter = match &context.limiter {
+            Some(limiter) => limiter,
+            None => return Ok(lease),
+        };
+
+        let mut counters = vec![(
+            traffic::inbound_key(&context.inbound),
+            context.inbound.limits,
+        )];
+        if !context.user.name.is_empty() {
+            counters.push((
+                traffic::user_key(&context.inbound, &context.user),
+                context.user.limits,
+            ));
+        }
+
+        for (key, limits) in counters {
+            if !limits.is_limited() {
+                continue;
+            }
+
+            // slots which are already taken get released by dropping the lease
+            limiter.acquire(&key, &lease.id, &limits).await?;
+            lease.keys.push(key);
+        }
+
+        if !lease.keys.is_empty() {
+            crate::common::spawn(renew(
+                limiter.clone(),
+                lease.id.clone(),
+                lease.keys.clone(),
+                lease.open.clone(),
+            ));
+        }
+        Ok(lease)
+    }
+}
+
+/// renews the lease until the connection is closed
+async fn renew(limiter: Limiter, id: String, keys: Vec<String>, open: Rc<Cell<bool>>) {
+    loop {
+        time::sleep(Duration::from_millis(LEASE_TTL / 2)).await;
+        if !open.get() {
+            return;
+        }
+        for key in &keys {
+            if let Err(e) = limiter.renew(key, &id).await {
+                crate::log!("[limit]: failed to renew {key}: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.open.set(false);
+        let limiter = match self.limiter.take() {
+            Some(limiter) if !self.keys.is_empty() => limiter,
+            _ => return,
+        };
+
+        let id = std::mem::take(&mut self.id);
+        let keys = std::mem::take(&mut self.keys);
+        self.background.spawn(async move {
+            for key in keys {
+                if let Err(e) = limiter.release(&key, &id).await {
+                    crate::log!("[limit]: failed to release {key}: {e}");
+                }
+            }
+        });
+    }
+}
+
+/// keeps the counters in the storage of the object, so they survive evictions
+#[durable_object]
+pub struct ConnectionLimiter {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for ConnectionLimiter {
+    fn new(state: State, _: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let request = req.json::<LeaseRequest>().await?;
+        let mut storage = self.state.storage();
+        let mut connections = storage
+            .get::<Connections>(CONNECTIONS_KEY)
+            .await
+            .unwrap_or_default();
+
+        let now = SystemClock.now();
+        let res = match req.path().as_str() {
+            "/acquire" => match connections.acquire(&request.id, &request.limits, now) {
+                Ok(()) => Response::ok("ok"),
+                Err(e) => return Response::error(e, 429),
+            },
+            "/renew" => {
+                connections.renew(&request.id, now);
+                Response::ok("ok")
+            }
+            "/release" => {
+                connections.release(&request.id);
+                Response::ok("ok")
+            }
+            _ => return Response::error("not found", 404),
+        };
+
+        storage.put(CONNECTIONS_KEY, &connections).await?;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    struct FakeClock(Rc<Cell<u64>>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_connections() {
+        let limits = Limits {
+            max_connections: 2,
+            connections_per_minute: 3,
+            ..Default::default()
+        };
+        let mut connections = Connections::default();
+
+        assert!(connections.acquire("a", &limits, 0).is_ok());
+        assert!(connections.acquire("b", &limits, 1_000).is_ok());
+        assert!(connections.acquire("c", &limits, 2_000).is_err());
+
+        connections.release("a");
+        assert!(connections.acquire("c", &limits, 3_000).is_ok());
+
+        // three connections have been opened during the last minute
+        connections.release("b");
+        assert!(connections.acquire("d", &limits, 4_000).is_err());
+        assert!(connections.acquire("d", &limits, 60_000).is_ok());
+
+        // a lease which isn't renewed runs out, as if its release got lost
+        connections.renew("d", 60_000 + LEASE_TTL / 2);
+        assert!(connections.acquire("e", &limits, 3_000 + LEASE_TTL).is_ok());
+        assert!(connections
+            .acquire("f", &limits, 3_000 + LEASE_TTL)
+            .is_err());
+
+        // the counters survive the object being evicted
+        let stored = serde_json::to_string(&connections).unwrap();
+        let connections: Connections = serde_json::from_str(&stored).unwrap();
+        assert_eq!(connections.active.len(), 2);
+    }
+
+    #[test]
+    fn test_shaper() {
+        let now = Rc::new(Cell::new(0));
+        let mut shaper = Shaper::new(1000, Box::new(FakeClock(now.clone())));
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(shaper.poll_acquire(&mut cx, 600), Poll::Ready(600));
+        assert_eq!(shaper.poll_acquire(&mut cx, 600), Poll::Ready(400));
+        assert_eq!(shaper.bucket.take(600, now.get()), Err(1));
+
+        now.set(250);
+        assert_eq!(shaper.poll_acquire(&mut cx, 600), Poll::Ready(250));
+        shaper.refund(50);
+        assert_eq!(shaper.poll_acquire(&mut cx, 600), Poll::Ready(50));
+
+        // the bucket never holds more than a second worth of tokens
+        now.set(10_000);
+        assert_eq!(shaper.bucket.take(5000, now.get()), Ok(1000));
+    }
+
+    #[test]
+    fn test_slow_refill() {
+        // a token and a half every 500ms, the halves add up
+        let mut bucket = TokenBucket::new(3, 0);
+        assert_eq!(bucket.take(3, 0), Ok(3));
+        assert_eq!(bucket.take(3, 500), Ok(1));
+        assert_eq!(bucket.take(3, 1000), Ok(2));
+        assert_eq!(bucket.take(3, 1200), Err(333));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::config::{Config, Inbound, Protocol};
+use crate::common::time;
+use crate::config::{Config, Inbound, InboundTransport, LinkOptions, Protocol, User};
 
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
 use serde::Serialize;
@@ -6,56 +7,386 @@ use serde_json::json;
 
 #[derive(Serialize)]
 pub struct Link {
-    links: Vec<String>,
+    pub links: Vec<String>,
 }
 
-pub fn generate_link(config: &Config, host: &str) -> Link {
-    let links = config
-        .inbound
-        .clone()
-        .into_iter()
-        .filter_map(|inbound| match inbound.protocol {
-            Protocol::Vless => Some(generate_vless_link(&inbound, host)),
-            Protocol::Vmess => Some(generate_vmess_link(&inbound, host)),
-            Protocol::Trojan => Some(generate_trojan_link(&inbound, host)),
+/// a single inbound user along with the address clients should connect to
+pub struct Share<'a> {
+    pub inbound: &'a Inbound,
+    pub user: User,
+    /// front address or clean ip to connect to
+    pub address: String,
+    pub port: u16,
+    /// host header of the websocket
+    pub host: String,
+    pub sni: String,
+    pub fingerprint: Option<String>,
+    pub alpn: Vec<String>,
+    pub remark: String,
+}
+
+impl<'a> Share<'a> {
+    pub fn uri(&self) -> Option<String> {
+        match self.inbound.protocol {
+            Protocol::Vless => Some(generate_vless_link(self)),
+            Protocol::Vmess => Some(generate_vmess_link(self)),
+            Protocol::Trojan => Some(generate_trojan_link(self)),
+            Protocol::Bepass => Some(generate_bepass_link(self)),
             _ => None,
+        }
+    }
+
+    /// tls and transport parameters shared by the vless and trojan links
+    fn params(&self) -> String {
+        let transport = match self.inbound.transport {
+            InboundTransport::Grpc => format!(
+                "type=grpc&serviceName={}&mode=gun",
+                encode_component(self.inbound.service_name())
+            ),
+            ref transport => format!("type={}&path={}", transport.network(), self.inbound.path),
+        };
+        let mut params = format!(
+            "security=tls&{transport}&sni={}&host={}",
+            encode_component(&self.sni),
+            encode_component(&self.host)
+        );
+        if let Some(fp) = &self.fingerprint {
+            params.push_str(&format!("&fp={}", encode_component(fp)));
+        }
+        if !self.alpn.is_empty() {
+            params.push_str(&format!("&alpn={}", encode_component(&self.alpn.join(","))));
+        }
+        params
+    }
+}
+
+/// collects the shares of every inbound, users are filtered by `filter` and
+/// expired users are skipped since they can't connect anymore
+pub fn shares<'a, F>(config: &'a Config, host: &str, filter: F) -> Vec<Share<'a>>
+where
+    F: Fn(&User) -> bool,
+{
+    let now = time::now();
+    let options = &config.link;
+    let addresses = match options.addresses.is_empty() {
+        true => vec![host.to_string()],
+        false => options.addresses.clone(),
+    };
+    let ports = match options.ports.is_empty() {
+        true => vec![443],
+        false => options.ports.clone(),
+    };
+
+    let worker = [host.to_string()];
+
+    let mut shares = Vec::new();
+    for inbound in &config.inbound {
+        // bepass takes the worker address as is, front addresses don't apply
+        let addresses = match inbound.protocol {
+            Protocol::Bepass => &worker[..],
+            _ => &addresses[..],
+        };
+        let users: Vec<User> = match inbound.protocol {
+            // bepass doesn't authenticate its users
+            Protocol::Bepass => vec![User {
+                name: Inbound::DEFAULT_USER.to_string(),
+                ..Default::default()
+            }],
+            _ => inbound
+                .users()
+                .into_iter()
+                .filter(|user| !user.is_expired(now) && filter(user))
+                .collect(),
+        };
+
+        for user in users {
+            for address in addresses {
+                for port in &ports {
+                    shares.push(Share {
+                        inbound,
+                        remark: remark(options, inbound, &user, address, *port),
+                        user: user.clone(),
+                        address: address.clone(),
+                        port: *port,
+                        host: options.host.clone().unwrap_or(host.to_string()),
+                        sni: options.sni.clone().unwrap_or(host.to_string()),
+                        fingerprint: options.fingerprint.clone(),
+                        alpn: options.alpn.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    shares
+}
+
+/// links of the top level credentials of the inbounds, the users have their
+/// own subscriptions since `/link` isn't authenticated
+pub fn generate_link(config: &Config, host: &str) -> Link {
+    let links = shares(config, host, |user| user.name == Inbound::DEFAULT_USER)
+        .iter()
+        .filter(|share| {
+            share.user.uuid == share.inbound.uuid && share.user.password == share.inbound.password
         })
+        .filter_map(Share::uri)
         .collect();
 
     Link { links }
 }
 
-fn generate_vless_link(config: &Inbound, host: &str) -> String {
+fn remark(
+    options: &LinkOptions,
+    inbound: &Inbound,
+    user: &User,
+    address: &str,
+    port: u16,
+) -> String {
+    let mut remark = match (&options.remark, user.name.as_str()) {
+        (Some(template), name) => template
+            .replace("{user}", name)
+            .replace(
+                "{protocol}",
+                &format!("{:?}", inbound.protocol).to_lowercase(),
+            )
+            .replace("{address}", address)
+            .replace("{port}", &port.to_string()),
+        (None, Inbound::DEFAULT_USER) => "tunl".to_string(),
+        (None, name) => format!("tunl-{name}"),
+    };
+
+    if let Some(expires_at) = &user.expires_at {
+        remark.push_str(&format!(" (expires {expires_at})"));
+    }
+
+    remark
+}
+
+pub fn encode_component(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// reverses the percent encoding of `encode_component`, invalid escapes are kept as is
+pub fn decode_component(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn generate_vless_link(share: &Share) -> String {
     format!(
-        "vless://{}@{}:443?type=ws&security=tls&path={}#tunl",
-        config.uuid, host, config.path
+        "vless://{}@{}:{}?{}#{}",
+        share.user.uuid,
+        share.address,
+        share.port,
+        share.params(),
+        encode_component(&share.remark)
     )
 }
 
-fn generate_vmess_link(config: &Inbound, host: &str) -> String {
-    let uuid = config.uuid.to_string();
-    let path = &config.path;
+fn generate_vmess_link(share: &Share) -> String {
+    let uuid = share.user.uuid.to_string();
+    // v2rayN keeps the service name of grpc in the path
+    let (path, kind) = match share.inbound.transport {
+        InboundTransport::Grpc => (share.inbound.service_name(), "gun"),
+        _ => (share.inbound.path.as_str(), "none"),
+    };
     let config = json!({
-        "ps": "tunl",
+        "ps": share.remark,
         "v": "2",
-        "add": host,
-        "port": "443",
+        "add": share.address,
+        "port": share.port.to_string(),
         "id": uuid,
         "path": path,
+        "host": share.host,
         "aid": "0",
         "scy": "zero",
-        "net": "ws",
-        "type": "none",
+        "net": share.inbound.transport.network(),
+        "type": kind,
         "tls": "tls",
-        "sni": "",
-        "alpn": ""}
+        "sni": share.sni,
+        "fp": share.fingerprint.clone().unwrap_or_default(),
+        "alpn": share.alpn.join(",")}
     );
     format!("vmess://{}", URL_SAFE.encode(config.to_string()))
 }
 
-fn generate_trojan_link(config: &Inbound, host: &str) -> String {
+fn generate_trojan_link(share: &Share) -> String {
     format!(
-        "trojan://{}@{}:443?security=tls&type=ws&path={}#tunl",
-        config.password, host, config.path
+        "trojan://{}@{}:{}?{}#{}",
+        encode_component(&share.user.password),
+        share.address,
+        share.port,
+        share.params(),
+        encode_component(&share.remark)
     )
 }
+
+fn generate_bepass_link(share: &Share) -> String {
+    // bepass takes the worker address as is
+    format!(
+        "https://{}:{}{}",
+        share.host, share.port, share.inbound.path
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_front_addresses() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [[inbound]]
+            protocol = "bepass"
+            path = "/bepass"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+
+            [link]
+            addresses = ["104.16.1.1", "104.17.2.2"]
+            ports = [443, 2053]
+            fingerprint = "chrome"
+            alpn = ["http/1.1"]
+            remark = "{protocol}-{address}:{port}"
+            "#,
+        );
+
+        let links = shares(&config, "tunl.workers.dev", |_| true);
+        assert_eq!(links.len(), 6);
+        assert_eq!(
+            links[1].uri().unwrap(),
+            "vless://0fbf4f81-2598-4b6a-a623-0ead4cb9efa8@104.16.1.1:2053?security=tls&type=ws\
+             &path=/vless&sni=tunl.workers.dev&host=tunl.workers.dev&fp=chrome&alpn=http%2F1.1\
+             #vless-104.16.1.1%3A2053"
+        );
+        assert_eq!(links[2].address, "104.17.2.2");
+        assert_eq!(links[2].remark, "vless-104.17.2.2:443");
+
+        // a single bepass link per port, whatever the front addresses
+        let bepass: Vec<String> = links[4..].iter().filter_map(Share::uri).collect();
+        assert_eq!(
+            bepass,
+            [
+                "https://tunl.workers.dev:443/bepass",
+                "https://tunl.workers.dev:2053/bepass"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_link() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [[inbound.users]]
+            name = "alice"
+            uuid = "1fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+
+            [[inbound.users]]
+            name = "default"
+            uuid = "2fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+
+            [[inbound]]
+            protocol = "trojan"
+            path = "/trojan"
+
+            [[inbound.users]]
+            name = "bob"
+            password = "secret"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+
+        // only the top level credentials, the users are never listed
+        let link = generate_link(&config, "tunl.workers.dev");
+        assert_eq!(link.links.len(), 1);
+        assert!(link.links[0].starts_with("vless://0fbf4f81-2598-4b6a-a623-0ead4cb9efa8@"));
+    }
+
+    #[test]
+    fn test_grpc_link() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "trojan"
+            password = "password"
+            path = "/trojan-grpc"
+            transport = "grpc"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+
+        let links = shares(&config, "tunl.workers.dev", |_| true);
+        assert_eq!(
+            links[0].uri().unwrap(),
+            "trojan://password@tunl.workers.dev:443?security=tls&type=grpc&serviceName=trojan-grpc\
+             &mode=gun&sni=tunl.workers.dev&host=tunl.workers.dev#tunl"
+        );
+    }
+
+    #[test]
+    fn test_httpupgrade_link() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/upgrade"
+            transport = "httpupgrade"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+
+        let links = shares(&config, "tunl.workers.dev", |_| true);
+        assert!(links[0]
+            .uri()
+            .unwrap()
+            .contains("type=httpupgrade&path=/upgrade"));
+    }
+}
@@ -0,0 +1,52 @@
+#![allow(unused)]
+
+mod admin;
+mod common;
+mod config;
+mod import;
+mod limit;
+mod link;
+mod proxy;
+mod qr;
+mod subscription;
+mod traffic;
+mod xray;
+
+use crate::config::Outbound;
+
+/// replaces the outbound of a config file with the one of a share link and
+/// prints the result, the `match` list of the current outbound is kept
+///
+/// usage: link_importer <config.toml> <share link>
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <config.toml> <share link>", args[0]);
+        std::process::exit(2);
+    }
+
+    let buf = std::fs::read_to_string(&args[1]).unwrap_or_else(|e| {
+        eprintln!("failed to read {}: {e}", args[1]);
+        std::process::exit(1);
+    });
+    let mut config: toml::Table = toml::from_str(&buf).unwrap_or_else(|e| {
+        eprintln!("invalid config: {e}");
+        std::process::exit(1);
+    });
+
+    let mut outbound = Outbound::from_link(&args[2]).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let current = config
+        .get("outbound")
+        .and_then(|o| o.get("match"))
+        .and_then(|m| m.clone().try_into().ok());
+    if let Some(r#match) = current {
+        outbound.r#match = r#match;
+    }
+
+    let outbound = toml::Value::try_from(&outbound).unwrap();
+    config.insert("outbound".to_string(), outbound);
+    print!("{}", toml::to_string_pretty(&config).unwrap());
+}
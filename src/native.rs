@@ -0,0 +1,264 @@
+use crate::config::{TextPolicy, WebSocketOptions};
+use crate::proxy::{close, Connector, Proxy};
+
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use bytes::{Buf, Bytes};
+use futures_util::{Sink, SinkExt, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::tungstenite::Message;
+use worker::{Result, Url};
+
+#[async_trait(?Send)]
+impl Proxy for TcpStream {
+    async fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// connects the outbounds through plain tokio tcp streams
+pub struct TcpConnector;
+
+#[async_trait(?Send)]
+impl Connector for TcpConnector {
+    async fn connect(&self, address: &str, port: u16) -> Result<Box<dyn Proxy>> {
+        let stream = TcpStream::connect((address, port)).await?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+// longest request head accepted before the upgrade
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// reads the head of an upgrade request, returns it along with whatever the
+/// client sent right after it and the length of the head
+pub async fn read_head<S>(stream: &mut S) -> std::io::Result<(Vec<u8>, usize)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok((buf, end + 4));
+        }
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(io_error("request head is too large"));
+        }
+
+        let mut chunk = [0u8; 1024];
+        match stream.read(&mut chunk).await? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+/// target of the request line (E.g. GET /vless?ed=2048 HTTP/1.1)
+fn request_target(head: &[u8]) -> Option<&str> {
+    let line = head.split(|b| *b == b'\n').next()?;
+    std::str::from_utf8(line).ok()?.split(' ').nth(1)
+}
+
+/// path of the request line (E.g. GET /vless HTTP/1.1), without the query
+pub fn request_path(head: &[u8]) -> Option<&str> {
+    request_target(head)?.split('?').next()
+}
+
+/// url of the request, put together from its target and host header
+pub fn request_url(head: &[u8]) -> Option<Url> {
+    let host = header(head, "host").unwrap_or("localhost");
+    Url::parse(&format!("http://{host}{}", request_target(head)?)).ok()
+}
+
+/// value of a header in the request head, names are case insensitive
+pub fn header<'a>(head: &'a [u8], name: &str) -> Option<&'a str> {
+    std::str::from_utf8(head)
+        .ok()?
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// replays bytes which have been read ahead before reading from the stream
+pub struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.prefix.has_remaining() {
+            let size = std::cmp::min(self.prefix.len(), buf.remaining());
+            buf.put_slice(&self.prefix.split_to(size));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+fn io_error(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+}
+
+/// exposes the binary messages of a websocket as a byte stream, writes are
+/// held back by the sink itself once its buffer is full
+pub struct WebSocketStream<S> {
+    ws: S,
+    buffer: Bytes,
+    max_frame_size: usize,
+    text: TextPolicy,
+    closing: bool,
+}
+
+impl<S> WebSocketStream<S> {
+    pub fn new(ws: S, options: WebSocketOptions) -> Self {
+        Self {
+            ws,
+            buffer: Bytes::new(),
+            max_frame_size: options.max_frame_size,
+            text: options.text,
+            closing: false,
+        }
+    }
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    /// closes the websocket with the given code unless it's already closing
+    pub async fn close(&mut self, code: u16, reason: &str) -> std::io::Result<()> {
+        if self.closing {
+            return Ok(());
+        }
+        self.closing = true;
+        self.ws
+            .send(Message::Close(Some(CloseFrame {
+                code: code.into(),
+                reason: reason.to_string().into(),
+            })))
+            .await
+            .map_err(io_error)
+    }
+}
+
+type WsResult = std::result::Result<Message, tokio_tungstenite::tungstenite::Error>;
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: Stream<Item = WsResult> + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        loop {
+            if !self.buffer.is_empty() {
+                let size = std::cmp::min(self.buffer.len(), buf.remaining());
+                buf.put_slice(&self.buffer[..size]);
+                self.buffer.advance(size);
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.buffer = data.into(),
+                // pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Text(text))) => match self.text {
+                    TextPolicy::Bytes => self.buffer = text.into_bytes().into(),
+                    TextPolicy::Reject => return Poll::Ready(Err(close::text_error())),
+                },
+                Some(Ok(Message::Close(Some(frame)))) if !close::is_clean(frame.code.into()) => {
+                    return Poll::Ready(Err(close::error(frame.code.into(), &frame.reason)))
+                }
+                Some(Ok(Message::Close(_) | Message::Frame(_))) | None => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        let size = match self.max_frame_size {
+            0 => buf.len(),
+            max => std::cmp::min(buf.len(), max),
+        };
+        let mut ws = Pin::new(&mut self.ws);
+        ready!(ws.as_mut().poll_ready(cx)).map_err(io_error)?;
+        ws.start_send(Message::Binary(buf[..size].to_vec()))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(io_error)
+    }
+
+    /// sends a normal close frame, reading goes on until the client answers it
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        if !self.closing {
+            let mut ws = Pin::new(&mut self.ws);
+            ready!(ws.as_mut().poll_ready(cx)).map_err(io_error)?;
+            ws.start_send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "upstream closed".into(),
+            })))
+            .map_err(io_error)?;
+            self.closing = true;
+        }
+
+        Pin::new(&mut self.ws).poll_flush(cx).map_err(io_error)
+    }
+}
@@ -0,0 +1,241 @@
+use crate::common::{self, time};
+use crate::config::{BalanceStrategy, Balancer, Outbound, Timeouts, WebSocketOptions};
+use crate::proxy::{self, Connector, RequestContext};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// seconds between two probes of an outbound when the balancer doesn't say
+pub const DEFAULT_PROBE_INTERVAL: u64 = 60;
+
+// weight of the newest sample in the moving average of the latencies
+const SMOOTHING: f64 = 0.3;
+
+#[derive(Default)]
+struct Stats {
+    active: usize,
+    /// connect latency in milliseconds, `None` until measured
+    latency: Option<f64>,
+    probed_at: u64,
+}
+
+// like the address health, the stats only cover the current isolate
+thread_local! {
+    static STATS: RefCell<HashMap<String, Stats>> = RefCell::new(HashMap::new());
+    static NEXT: RefCell<HashMap<String, usize>> = RefCell::new(HashMap::new());
+}
+
+fn with_stats<T>(tag: &str, f: impl FnOnce(&mut Stats) -> T) -> T {
+    STATS.with(|stats| f(stats.borrow_mut().entry(tag.to_string()).or_default()))
+}
+
+/// orders the outbounds of a balancer by its strategy, probing the ones whose
+/// latency is out of date in the background
+pub fn order(
+    balancer: &Balancer,
+    mut members: Vec<Outbound>,
+    context: &RequestContext,
+    timeouts: &Timeouts,
+) -> Vec<Outbound> {
+    if let Some(target) = &balancer.probe {
+        let interval = balancer.probe_interval.unwrap_or(DEFAULT_PROBE_INTERVAL) * 1000;
+        let now = time::now();
+        for outbound in &members {
+            let due = with_stats(&outbound.tag, |stats| {
+                let due = now.saturating_sub(stats.probed_at) >= interval;
+                if due {
+                    stats.probed_at = now;
+                }
+                due
+            });
+            if due {
+                let probe = probe(
+                    outbound.clone(),
+                    target.clone(),
+                    context.connector.clone(),
+                    *timeouts,
+                    context.websocket,
+                );
+                common::spawn(async move {
+                    let _ = probe.await;
+                });
+            }
+        }
+    }
+
+    match balancer.strategy {
+        BalanceStrategy::Random => {
+            let start = fastrand::usize(..members.len());
+            members.rotate_left(start);
+        }
+        BalanceStrategy::RoundRobin => {
+            let start = NEXT.with(|next| {
+                let mut next = next.borrow_mut();
+                let next = next.entry(balancer.tag.clone()).or_default();
+                *next = next.wrapping_add(1);
+                *next - 1
+            }) % members.len();
+            members.rotate_left(start);
+        }
+        BalanceStrategy::LeastConnections => {
+            members.sort_by_key(|outbound| with_stats(&outbound.tag, |stats| stats.active));
+        }
+        // the unmeasured ones go first to get a latency
+        BalanceStrategy::LeastLatency => {
+            members.sort_by(|a, b| {
+                let a = with_stats(&a.tag, |stats| stats.latency.unwrap_or_default());
+                let b = with_stats(&b.tag, |stats| stats.latency.unwrap_or_default());
+                a.total_cmp(&b)
+            });
+        }
+    }
+    members
+}
+
+/// adds a connect latency to the moving average of an outbound
+pub fn record_latency(tag: &str, millis: u64) {
+    if tag.is_empty() {
+        return;
+    }
+
+    with_stats(tag, |stats| {
+        let sample = millis as f64;
+        stats.latency = Some(match stats.latency {
+            Some(latency) => latency + SMOOTHING * (sample - latency),
+            None => sample,
+        });
+    });
+}
+
+/// counts an open connection of an outbound until dropped
+pub struct Connection(Rc<str>);
+
+impl Connection {
+    pub fn open(tag: &str) -> Option<Self> {
+        if tag.is_empty() {
+            return None;
+        }
+
+        with_stats(tag, |stats| stats.active += 1);
+        Some(Self(tag.into()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        with_stats(&self.0, |stats| {
+            stats.active = stats.active.saturating_sub(1)
+        });
+    }
+}
+
+/// slows an outbound down as much as the connect timeout after it failed
+pub fn record_failure(tag: &str, timeouts: &Timeouts) {
+    let timeout = match timeouts.connect {
+        0 => Timeouts::default().connect,
+        timeout => timeout,
+    };
+    record_latency(tag, timeout * 1000);
+}
+
+/// opens a tunnel through the outbound to the target to measure its latency,
+/// the probes are neither metered nor counted against the limits
+pub async fn probe(
+    outbound: Outbound,
+    target: String,
+    connector: Option<Rc<dyn Connector>>,
+    timeouts: Timeouts,
+    websocket: WebSocketOptions,
+) -> worker::Result<()> {
+    let (address, port) = target
+        .rsplit_once(':')
+        .and_then(|(address, port)| Some((address, port.parse::<u16>().ok()?)))
+        .ok_or(worker::Error::RustError(format!(
+            "invalid probe target {target}"
+        )))?;
+    let context = RequestContext {
+        address: address.trim_matches(['[', ']']).to_string(),
+        port,
+        connector,
+        websocket,
+        ..Default::default()
+    };
+
+    // the latency is recorded while connecting
+    proxy::open_outbound(&context, &outbound, &timeouts)
+        .await
+        .map(|_| ())
+        .map_err(|e| {
+            crate::log!("[balancer] probing {} failed: {e}", outbound.tag);
+            e
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(tags: &[&str]) -> Vec<Outbound> {
+        tags.iter()
+            .map(|tag| Outbound {
+                tag: tag.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn tags(outbounds: Vec<Outbound>) -> Vec<String> {
+        outbounds.into_iter().map(|outbound| outbound.tag).collect()
+    }
+
+    #[test]
+    fn test_order() {
+        let context = RequestContext::default();
+        let timeouts = Timeouts::default();
+        let balancer = |strategy| Balancer {
+            tag: format!("{strategy:?}"),
+            strategy,
+            ..Default::default()
+        };
+
+        let round_robin = balancer(BalanceStrategy::RoundRobin);
+        let group = members(&["rr-a", "rr-b"]);
+        assert_eq!(
+            tags(order(&round_robin, group.clone(), &context, &timeouts)),
+            ["rr-a", "rr-b"]
+        );
+        assert_eq!(
+            tags(order(&round_robin, group, &context, &timeouts)),
+            ["rr-b", "rr-a"]
+        );
+
+        let least_connections = balancer(BalanceStrategy::LeastConnections);
+        let busy = Connection::open("lc-a");
+        let group = members(&["lc-a", "lc-b"]);
+        assert_eq!(
+            tags(order(
+                &least_connections,
+                group.clone(),
+                &context,
+                &timeouts
+            )),
+            ["lc-b", "lc-a"]
+        );
+        drop(busy);
+        assert_eq!(
+            tags(order(&least_connections, group, &context, &timeouts)),
+            ["lc-a", "lc-b"]
+        );
+
+        let least_latency = balancer(BalanceStrategy::LeastLatency);
+        record_latency("ll-a", 300);
+        record_latency("ll-b", 100);
+        record_latency("ll-b", 1000);
+        let group = members(&["ll-a", "ll-b", "ll-c"]);
+        assert_eq!(
+            tags(order(&least_latency, group, &context, &timeouts)),
+            ["ll-c", "ll-a", "ll-b"]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::proxy::Network;
+use crate::proxy::{close, Network};
 
 use worker::*;
 
@@ -9,10 +9,10 @@ pub struct Header {
     pub port: u16,
 }
 
-pub fn decode_request_header(request: &Request) -> Result<Header> {
+/// reads the target from the query of the websocket url (E.g. ?host=1.1.1.1&port=443&net=tcp)
+pub fn decode_request_header(url: &Url) -> Result<Header> {
     let mut header = Header::default();
 
-    let url = request.url()?;
     let mut pairs = url.query_pairs();
 
     while let Some((k, v)) = pairs.next() {
@@ -21,7 +21,7 @@ pub fn decode_request_header(request: &Request) -> Result<Header> {
             "port" => {
                 header.port = v
                     .parse::<u16>()
-                    .map_err(|_| Error::RustError("invalid port number".to_string()))?;
+                    .map_err(|_| close::malformed("invalid port number"))?;
             }
             "net" => {
                 header.network = Network::from_str(&v)?;
@@ -34,3 +34,24 @@ pub fn decode_request_header(request: &Request) -> Result<Header> {
 
     Ok(header)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(url: &str) -> Result<Header> {
+        decode_request_header(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_decode_request_header() {
+        let header =
+            decode("https://tunl.workers.dev/bepass?host=1.1.1.1&port=53&net=udp&x=1").unwrap();
+        assert_eq!(header.address, "1.1.1.1");
+        assert_eq!(header.port, 53);
+        assert!(matches!(header.network, Network::Udp));
+
+        assert!(decode("https://tunl.workers.dev/bepass?host=1.1.1.1&port=70000").is_err());
+        assert!(decode("https://tunl.workers.dev/bepass?host=1.1.1.1&net=quic").is_err());
+    }
+}
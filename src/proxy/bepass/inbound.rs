@@ -1,5 +1,5 @@
 use crate::config::Config;
-use crate::proxy::{bepass::encoding, ws::WebSocketStream, Proxy, RequestContext};
+use crate::proxy::{bepass::encoding, timeout, Proxy, RequestContext};
 
 use std::pin::Pin;
 use std::sync::Arc;
@@ -11,32 +11,30 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use worker::*;
 
 pin_project! {
-    pub struct BepassStream<'a> {
+    pub struct BepassStream<S> {
         pub config: Arc<Config>,
         pub context: RequestContext,
-        pub ws: WebSocketStream<'a>,
+        pub stream: S,
     }
 }
 
-unsafe impl<'a> Send for BepassStream<'a> {}
-
-impl<'a> BepassStream<'a> {
-    pub fn new(config: Arc<Config>, context: RequestContext, ws: WebSocketStream<'a>) -> Self {
+impl<S> BepassStream<S> {
+    pub fn new(config: Arc<Config>, context: RequestContext, stream: S) -> Self {
         Self {
             config,
             context,
-            ws,
+            stream,
         }
     }
 }
 
-#[async_trait]
-impl<'a> Proxy for BepassStream<'a> {
+#[async_trait(?Send)]
+impl<S: AsyncRead + AsyncWrite + Unpin> Proxy for BepassStream<S> {
     async fn process(&mut self) -> Result<()> {
-        let request = self.context.request.as_ref().ok_or(Error::RustError(
-            "failed to retrive request context".to_string(),
+        let url = self.context.url.as_ref().ok_or(Error::RustError(
+            "the url of the request is unknown".to_string(),
         ))?;
-        let header = encoding::decode_request_header(request)?;
+        let header = encoding::decode_request_header(url)?;
 
         let mut context = self.context.clone();
         {
@@ -45,41 +43,47 @@ impl<'a> Proxy for BepassStream<'a> {
             context.network = header.network;
         }
 
-        let outbound = self.config.dispatch_outbound(&context);
-        let mut upstream = crate::proxy::connect_outbound(context, outbound).await?;
+        let timeouts = self.config.timeouts;
+        let outbounds = self.config.dispatch_outbound(&context)?;
+        let mut upstream = crate::proxy::connect_outbound(context, outbounds, &timeouts).await?;
 
-        tokio::io::copy_bidirectional(self, &mut upstream).await?;
+        timeout::copy_bidirectional(&timeouts, self, &mut upstream).await?;
 
         Ok(())
     }
 }
 
-impl<'a> AsyncRead for BepassStream<'a> {
+impl<S: AsyncRead + Unpin> AsyncRead for BepassStream<S> {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        let mut pinned = std::pin::pin!(&mut self.ws);
+        let mut pinned = std::pin::pin!(&mut self.stream);
         pinned.as_mut().poll_read(cx, buf)
     }
 }
 
-impl<'a> AsyncWrite for BepassStream<'a> {
+impl<S: AsyncWrite + Unpin> AsyncWrite for BepassStream<S> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        let mut pinned = std::pin::pin!(&mut self.ws);
+        let mut pinned = std::pin::pin!(&mut self.stream);
         pinned.as_mut().poll_write(cx, buf)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let mut pinned = std::pin::pin!(&mut self.stream);
+        pinned.as_mut().poll_flush(cx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        let mut pinned = std::pin::pin!(&mut self.stream);
+        pinned.as_mut().poll_shutdown(cx)
     }
 }
@@ -9,7 +9,7 @@ use worker::*;
 
 pub struct BlackholeStream;
 
-#[async_trait]
+#[async_trait(?Send)]
 impl Proxy for BlackholeStream {
     async fn process(&mut self) -> Result<()> {
         Ok(())
@@ -0,0 +1,100 @@
+use crate::config::{Outbound, Timeouts, WebSocketOptions};
+use crate::proxy::{self, Connector, Proxy, RequestContext};
+
+use std::rc::Rc;
+
+use async_trait::async_trait;
+use worker::*;
+
+/// connects through another outbound, so the stream of an outbound runs on top
+/// of the tunnel the hop opens to its address (E.g. vless over a relay)
+pub struct Detour {
+    pub hop: Outbound,
+    /// connects the hop itself, which may be a detour again
+    pub connector: Rc<dyn Connector>,
+    pub timeouts: Timeouts,
+    pub websocket: WebSocketOptions,
+}
+
+#[async_trait(?Send)]
+impl Connector for Detour {
+    async fn connect(&self, address: &str, port: u16) -> Result<Box<dyn Proxy>> {
+        let context = RequestContext {
+            address: address.to_string(),
+            port,
+            connector: Some(self.connector.clone()),
+            websocket: self.websocket,
+            ..Default::default()
+        };
+        proxy::open_outbound(&context, &self.hop, &self.timeouts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::proxy::testing::{block_on, users, PipeConnector};
+    use crate::proxy::vless::encoding;
+
+    use tokio::io::AsyncBufReadExt;
+
+    #[test]
+    fn test_vless_over_relay() {
+        let config = Config::new(
+            r#"
+            inbound = []
+
+            [outbound]
+            tag = "upstream"
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            addresses = ["vless.example.com"]
+            port = 443
+            via = "relay"
+            match = ["0.0.0.0/0"]
+
+            [[outbounds]]
+            tag = "relay"
+            protocol = "relay_v1"
+            addresses = ["10.0.0.1"]
+            port = 6666
+            match = []
+            "#,
+        );
+        let (upstream, remote) = tokio::io::duplex(1024);
+        let connector = Rc::new(PipeConnector::new(upstream));
+        let context = RequestContext {
+            address: "1.2.3.4".to_string(),
+            port: 80,
+            connector: Some(connector.clone()),
+            ..Default::default()
+        };
+
+        let outbound = config.dispatch_outbound(&context).unwrap().pop().unwrap();
+        assert_eq!(outbound.detour.as_ref().unwrap().tag, "relay");
+
+        let (line, header) = block_on(async {
+            let _stream = proxy::open_outbound(&context, &outbound, &config.timeouts)
+                .await
+                .unwrap();
+
+            // the relay is told to reach the vless server, which gets the destination
+            let mut remote = tokio::io::BufReader::new(remote);
+            let mut line = String::new();
+            remote.read_line(&mut line).await.unwrap();
+            (
+                line,
+                encoding::decode_request_header(&mut remote, &users()).await,
+            )
+        });
+        assert_eq!(
+            connector.target.take(),
+            Some(("10.0.0.1".to_string(), 6666))
+        );
+        assert_eq!(line, "tcp@vless.example.com$443\r\n");
+        let header = header.unwrap();
+        assert_eq!(header.address, "1.2.3.4");
+        assert_eq!(header.port, 80);
+    }
+}
@@ -0,0 +1,78 @@
+use worker::Error;
+
+// close codes of rfc 6455 section 7.4.1
+pub const NORMAL: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+pub const NO_STATUS: u16 = 1005;
+pub const POLICY_VIOLATION: u16 = 1008;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+const UNAUTHORIZED: &str = "unauthorized: ";
+const MALFORMED: &str = "malformed request: ";
+
+/// the client is not allowed in: unknown credentials, expired or over its limits
+pub fn unauthorized(reason: impl std::fmt::Display) -> Error {
+    Error::RustError(format!("{UNAUTHORIZED}{reason}"))
+}
+
+/// the request header could not be decoded
+pub fn malformed(reason: impl std::fmt::Display) -> Error {
+    Error::RustError(format!("{MALFORMED}{reason}"))
+}
+
+/// whether a close received from the client ends the stream like a regular eof
+pub fn is_clean(code: u16) -> bool {
+    matches!(code, NORMAL | GOING_AWAY | NO_STATUS)
+}
+
+/// turns an abnormal close of the client into an i/o error
+pub fn error(code: u16, reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionAborted,
+        format!("websocket closed with code {code}: {reason}"),
+    )
+}
+
+/// rejects text frames when they are not accepted as payload
+pub fn text_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected text message")
+}
+
+/// close code and reason telling the client why its request failed, the
+/// details stay in the logs
+pub fn reason(e: &Error) -> (u16, &'static str) {
+    match e {
+        Error::RustError(msg) if msg.starts_with(UNAUTHORIZED) => {
+            (POLICY_VIOLATION, "unauthorized")
+        }
+        Error::RustError(msg) if msg.starts_with(MALFORMED) => {
+            (PROTOCOL_ERROR, "malformed request")
+        }
+        Error::Io(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            (UNSUPPORTED_DATA, "unsupported data")
+        }
+        _ => (INTERNAL_ERROR, "internal error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason() {
+        let code = |e: Error| reason(&e).0;
+        assert_eq!(code(unauthorized("invalid password")), POLICY_VIOLATION);
+        assert_eq!(code(malformed("invalid address")), PROTOCOL_ERROR);
+        assert_eq!(code(Error::Io(text_error())), UNSUPPORTED_DATA);
+        assert_eq!(
+            code(Error::RustError("timeout".to_string())),
+            INTERNAL_ERROR
+        );
+
+        assert!(is_clean(NORMAL) && is_clean(NO_STATUS));
+        assert!(!is_clean(POLICY_VIOLATION));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::common::time;
+use crate::config::{Outbound, Strategy};
+use crate::proxy::RequestContext;
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// seconds a failed address is skipped for when the outbound doesn't say
+pub const DEFAULT_COOLDOWN: u64 = 30;
+
+// the health of the addresses is only remembered by the current isolate
+thread_local! {
+    static FAILED: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static NEXT: Cell<usize> = const { Cell::new(0) };
+}
+
+fn hash(value: impl Hash) -> usize {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+fn key(address: &str, port: u16) -> String {
+    format!("{address}:{port}")
+}
+
+/// the addresses of an outbound in the order they should be tried: starting at
+/// the one picked by the strategy, the ones cooling down after a failure last
+pub fn candidates(outbound: &Outbound, context: &RequestContext) -> Vec<String> {
+    let addresses = &outbound.addresses;
+    if addresses.is_empty() {
+        return vec![context.address.clone()];
+    }
+
+    let start = match outbound.strategy {
+        Strategy::Random => fastrand::usize(..addresses.len()),
+        Strategy::RoundRobin => NEXT.with(|next| next.replace(next.get().wrapping_add(1))),
+        Strategy::StickyClient if !context.client.is_empty() => hash(&context.client),
+        Strategy::StickyClient => hash(&context.user.name),
+        Strategy::StickyDestination => hash((&context.address, context.port)),
+    } % addresses.len();
+
+    let mut addresses = addresses.clone();
+    addresses.rotate_left(start);
+
+    // still tried as a last resort, the cooldown may be overly cautious
+    let now = time::now();
+    FAILED.with(|failed| {
+        let failed = failed.borrow();
+        addresses.sort_by_key(|address| {
+            failed
+                .get(&key(address, outbound.port))
+                .is_some_and(|until| *until > now)
+        });
+    });
+    addresses
+}
+
+/// skips the address for the cooldown of the outbound
+pub fn mark_failed(outbound: &Outbound, address: &str) {
+    let cooldown = outbound.cooldown.unwrap_or(DEFAULT_COOLDOWN) * 1000;
+    FAILED.with(|failed| {
+        failed
+            .borrow_mut()
+            .insert(key(address, outbound.port), time::now() + cooldown);
+    });
+}
+
+pub fn mark_healthy(outbound: &Outbound, address: &str) {
+    FAILED.with(|failed| failed.borrow_mut().remove(&key(address, outbound.port)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relays(strategy: Strategy) -> Outbound {
+        Outbound {
+            addresses: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            port: 1000 + strategy as u16,
+            strategy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let context = RequestContext {
+            address: "example.com".to_string(),
+            port: 443,
+            client: "203.0.113.7".to_string(),
+            ..Default::default()
+        };
+
+        let outbound = relays(Strategy::RoundRobin);
+        let first = candidates(&outbound, &context);
+        let second = candidates(&outbound, &context);
+        assert_eq!(first.len(), 3);
+        assert_ne!(first[0], second[0]);
+
+        for strategy in [Strategy::StickyClient, Strategy::StickyDestination] {
+            let outbound = relays(strategy);
+            assert_eq!(
+                candidates(&outbound, &context),
+                candidates(&outbound, &context)
+            );
+        }
+
+        // failed addresses move to the end until they are healthy again
+        let outbound = relays(Strategy::StickyClient);
+        let picked = candidates(&outbound, &context)[0].clone();
+        mark_failed(&outbound, &picked);
+        let order = candidates(&outbound, &context);
+        assert_ne!(order[0], picked);
+        assert_eq!(order[2], picked);
+
+        mark_healthy(&outbound, &picked);
+        assert_eq!(candidates(&outbound, &context)[0], picked);
+    }
+}
@@ -0,0 +1,353 @@
+use crate::config::{Config, Inbound, InboundTransport};
+use crate::proxy::close;
+
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use worker::Result;
+
+// largest hunk written at once, far below the 4MiB message limit of grpc
+const MAX_HUNK_SIZE: usize = 64 * 1024;
+
+// largest message accepted from a client, the default limit of grpc
+const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// finds the grpc inbound of a request path, the gun service of an inbound
+/// is served at /{service name}/Tun
+pub fn dispatch(config: &Config, path: &str) -> Option<Inbound> {
+    let service = path.strip_prefix('/')?.strip_suffix("/Tun")?;
+    config
+        .inbound
+        .iter()
+        .find(|inbound| {
+            inbound.transport == InboundTransport::Grpc && inbound.service_name() == service
+        })
+        .cloned()
+}
+
+/// wraps the data in a `Hunk` message of a length-prefixed grpc frame
+pub fn encode(data: &[u8], buf: &mut BytesMut) {
+    let mut len = [0u8; 10];
+    let varint = encode_varint(data.len() as u64, &mut len);
+
+    buf.put_u8(0);
+    buf.put_u32((1 + varint.len() + data.len()) as u32);
+    buf.put_u8(0x0a);
+    buf.put_slice(varint);
+    buf.put_slice(data);
+}
+
+fn encode_varint(mut value: u64, buf: &mut [u8; 10]) -> &[u8] {
+    let mut i = 0;
+    loop {
+        buf[i] = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+        buf[i] |= 0x80;
+        i += 1;
+    }
+    &buf[..=i]
+}
+
+fn decode_varint(buf: &mut Bytes) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            break;
+        }
+        let byte = buf.get_u8();
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(malformed("invalid varint"))
+}
+
+fn malformed(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        close::malformed(format!("grpc: {reason}")).to_string(),
+    )
+}
+
+/// takes the data of the next complete frame out of the buffer, `None` if it
+/// hasn't been received entirely yet
+pub fn decode(buf: &mut BytesMut) -> std::io::Result<Option<Bytes>> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    if buf[0] != 0 {
+        return Err(malformed("compressed messages are not supported"));
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(malformed("message too large"));
+    }
+    if buf.len() < 5 + len {
+        return Ok(None);
+    }
+    buf.advance(5);
+    let mut message = buf.split_to(len).freeze();
+
+    // the data is field 1 of the hunk, other fields are skipped
+    let mut data = BytesMut::new();
+    while message.has_remaining() {
+        let key = decode_varint(&mut message)?;
+        let size = match key & 0x07 {
+            0 => {
+                decode_varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => match decode_varint(&mut message)? {
+                size if size > message.remaining() as u64 => {
+                    return Err(malformed("truncated message"))
+                }
+                size => size as usize,
+            },
+            5 => 4,
+            _ => return Err(malformed("invalid wire type")),
+        };
+        if size > message.remaining() {
+            return Err(malformed("truncated message"));
+        }
+        let field = message.split_to(size);
+        if key == 0x0a {
+            data.put_slice(&field);
+        }
+    }
+    Ok(Some(data.freeze()))
+}
+
+/// the tunnel of a gun call, read from the hunks of the request body and
+/// written as hunks to the response body
+pub struct GrpcStream<B> {
+    body: B,
+    raw: BytesMut,
+    payload: Bytes,
+    response: DuplexStream,
+    /// frame which hasn't been passed to the response yet
+    pending: BytesMut,
+}
+
+impl<B> GrpcStream<B>
+where
+    B: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    pub fn new(body: B) -> (Self, ResponseBody) {
+        let (response, far) = tokio::io::duplex(2 * MAX_HUNK_SIZE);
+        let stream = Self {
+            body,
+            raw: BytesMut::new(),
+            payload: Bytes::new(),
+            response,
+            pending: BytesMut::new(),
+        };
+        let body = ResponseBody {
+            pipe: far,
+            buffer: BytesMut::zeroed(2 * MAX_HUNK_SIZE),
+        };
+        (stream, body)
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.response).poll_write(cx, &self.pending))?;
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<B> AsyncRead for GrpcStream<B>
+where
+    B: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        loop {
+            let size = std::cmp::min(self.payload.len(), buf.remaining());
+            if size > 0 {
+                buf.put_slice(&self.payload.split_to(size));
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(data) = decode(&mut self.raw)? {
+                self.payload = data;
+                continue;
+            }
+            match ready!(Pin::new(&mut self.body).poll_next(cx)) {
+                Some(Ok(chunk)) => self.raw.put_slice(&chunk),
+                Some(Err(e)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        e.to_string(),
+                    )))
+                }
+                // the client is done sending, a partial frame is dropped
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<B> AsyncWrite for GrpcStream<B>
+where
+    B: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    /// frames at most one hunk, it's passed on by the next write or a flush
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        ready!(self.poll_pending(cx))?;
+
+        let size = std::cmp::min(buf.len(), MAX_HUNK_SIZE);
+        encode(&buf[..size], &mut self.pending);
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.response).poll_flush(cx)
+    }
+
+    /// ends the response body, grpc has no way to half-close the stream of
+    /// the server on its own
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.response).poll_shutdown(cx)
+    }
+}
+
+/// the frames written to a `GrpcStream`, it ends once the stream is dropped
+pub struct ResponseBody {
+    pipe: DuplexStream,
+    // read into on every poll, only the bytes read get copied out
+    buffer: BytesMut,
+}
+
+impl Stream for ResponseBody {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut buf = ReadBuf::new(&mut this.buffer);
+        match ready!(Pin::new(&mut this.pipe).poll_read(cx, &mut buf)) {
+            Ok(()) if buf.filled().is_empty() => Poll::Ready(None),
+            Ok(()) => Poll::Ready(Some(Ok(buf.filled().to_vec()))),
+            Err(e) => Poll::Ready(Some(Err(e.into()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::{block_on, PipeConnector};
+    use crate::proxy::{self, RequestContext};
+
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    use futures_util::{future, StreamExt};
+    use sha2::{Digest, Sha224};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_codec() {
+        let mut buf = BytesMut::new();
+        encode(&[7u8; 300], &mut buf);
+        assert_eq!(&buf[..8], &[0, 0, 0, 1, 47, 0x0a, 0xac, 0x02]);
+
+        // a frame split over several chunks
+        let mut partial = buf.split_to(100);
+        assert_eq!(decode(&mut partial).unwrap(), None);
+        partial.unsplit(buf);
+        assert_eq!(decode(&mut partial).unwrap().unwrap(), vec![7u8; 300]);
+        assert!(partial.is_empty());
+
+        // unknown fields are skipped
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 6, 0x10, 0x01, 0x0a, 0x02, b'h', b'i', 0][..]);
+        assert_eq!(decode(&mut buf).unwrap().unwrap(), &b"hi"[..]);
+        assert_eq!(&buf[..], &[0]);
+
+        let mut buf = BytesMut::from(&[1, 0, 0, 0, 0][..]);
+        assert!(decode(&mut buf).is_err());
+
+        // a frame over the limit is rejected before it's buffered
+        let mut buf = BytesMut::from(&[0, 0x00, 0x40, 0x00, 0x01][..]);
+        assert!(decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&[0, 0xff, 0xff, 0xff, 0xff][..]);
+        assert!(decode(&mut buf).is_err());
+
+        // a field longer than the message, its size doesn't fit in 32 bits
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 7, 0x0a, 0x80, 0x80, 0x80, 0x80, 0x10, 0][..]);
+        assert!(decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_tunnel() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "trojan"
+            password = "password"
+            path = "/trojan"
+            transport = "grpc"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+        let inbound = dispatch(&config, "/trojan/Tun").unwrap();
+        assert!(dispatch(&config, "/trojan").is_none());
+
+        let password = crate::hex!(&crate::sha224!("password")[..]);
+        let mut request = password.into_bytes();
+        request.extend_from_slice(b"\r\n\x01\x01\x0a\x00\x00\x01\x00\x50\r\nrequest");
+        let mut body = BytesMut::new();
+        encode(&request[..20], &mut body);
+        encode(&request[20..], &mut body);
+        // the hunks are cut at arbitrary places by the network
+        let chunks: Vec<Result<Vec<u8>>> = body.chunks(9).map(|c| Ok(c.to_vec())).collect();
+
+        let (stream, response) = GrpcStream::new(futures_util::stream::iter(chunks));
+        let (upstream, mut remote) = tokio::io::duplex(1024);
+        let context = RequestContext {
+            inbound,
+            connector: Some(Rc::new(PipeConnector::new(upstream))),
+            ..Default::default()
+        };
+
+        let remote = async move {
+            let mut request = Vec::new();
+            remote.read_to_end(&mut request).await.unwrap();
+            remote.write_all(b"response").await.unwrap();
+            remote.shutdown().await.unwrap();
+            request
+        };
+        let tunnel = proxy::process(Arc::new(config), context, stream);
+        let response = response.map(|chunk| chunk.unwrap()).concat();
+        let (result, request, response) = block_on(future::join3(tunnel, remote, response));
+        result.unwrap();
+        assert_eq!(request, b"request");
+
+        let mut response = BytesMut::from(&response[..]);
+        assert_eq!(decode(&mut response).unwrap().unwrap(), &b"response"[..]);
+    }
+}
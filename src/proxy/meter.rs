@@ -0,0 +1,279 @@
+use crate::config::Quota;
+use crate::limit::{self, Lease, Shaper};
+use crate::proxy::{balancer::Connection, close, Background, Proxy, RequestContext};
+use crate::traffic::{self, Report, Traffic, Usage};
+
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+// usage is reported every this many bytes, and as much budget is reserved ahead
+const REPORT_INTERVAL: u64 = 1024 * 1024;
+
+/// the budget reserved for a connection before it's opened
+pub struct Budget {
+    id: String,
+    /// `None` means unlimited
+    bytes: Option<u64>,
+}
+
+/// where the usage of a connection is reported to
+#[derive(Clone)]
+struct Reporter {
+    traffic: Traffic,
+    id: String,
+    inbound_key: String,
+    user_key: Option<String>,
+    quota: Quota,
+}
+
+impl Reporter {
+    fn new(context: &RequestContext, id: String) -> Option<Self> {
+        let user_key = match context.user.name.is_empty() {
+            true => None,
+            false => Some(traffic::user_key(&context.inbound, &context.user)),
+        };
+        Some(Self {
+            traffic: context.traffic.clone()?,
+            id,
+            inbound_key: traffic::inbound_key(&context.inbound),
+            user_key,
+            quota: context.user.quota,
+        })
+    }
+
+    /// books the usage and replaces the reservation of the connection with
+    /// `reserve` bytes, returns the budget granted by the user counter
+    async fn report(self, usage: Usage, reserve: u64) -> Result<Option<u64>> {
+        if usage.total() > 0 {
+            let report = Report {
+                usage,
+                ..Default::default()
+            };
+            self.traffic.report(&self.inbound_key, &report).await?;
+        }
+
+        let key = match &self.user_key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let report = Report {
+            id: self.id,
+            usage,
+            reserve,
+            quota: self.quota,
+        };
+        Ok(self.traffic.report(key, &report).await?.granted)
+    }
+}
+
+/// counts the traffic going through an outbound stream, the usage is reported
+/// as it goes and the connection is cut once the reserved budget is used up
+pub struct MeteredStream {
+    pub stream: Box<dyn Proxy>,
+    /// usage since the last report
+    unreported: Usage,
+    /// bytes left of the reserved budget, `None` means unlimited
+    budget: Option<u64>,
+    reporter: Option<Reporter>,
+    reporting: Option<LocalBoxFuture<'static, Result<Option<u64>>>>,
+    background: Background,
+    shaper: Option<Shaper>,
+    // holds the connection slots until the stream is closed
+    _lease: Lease,
+    _connection: Option<Connection>,
+}
+
+impl MeteredStream {
+    pub fn new(
+        context: &RequestContext,
+        stream: Box<dyn Proxy>,
+        budget: Budget,
+        lease: Lease,
+        connection: Option<Connection>,
+    ) -> Self {
+        Self {
+            stream,
+            unreported: Usage::default(),
+            budget: budget.bytes,
+            reporter: Reporter::new(context, budget.id),
+            reporting: None,
+            background: context.background.clone(),
+            shaper: limit::shaper(context),
+            _lease: lease,
+            _connection: connection,
+        }
+    }
+
+    /// reports the usage once the budget is used up or enough bytes went
+    /// through, transfers wait for the report to be done
+    fn poll_report(&mut self, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        if self.reporting.is_none() {
+            let due = self.budget == Some(0) || self.unreported.total() >= REPORT_INTERVAL;
+            let reporter = match &self.reporter {
+                Some(reporter) if due => reporter.clone(),
+                _ => return Poll::Ready(Ok(())),
+            };
+            let usage = std::mem::take(&mut self.unreported);
+            self.reporting = Some(Box::pin(reporter.report(usage, REPORT_INTERVAL)));
+        }
+
+        let result = ready!(self.reporting.as_mut().unwrap().as_mut().poll(cx));
+        self.reporting = None;
+        match result {
+            Ok(granted) => self.budget = granted,
+            // a user with a quota can't go on without a budget
+            Err(e) if self.budget.is_some() => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e.to_string(),
+                )))
+            }
+            Err(e) => crate::log!("[traffic]: failed to report usage: {e}"),
+        }
+
+        match self.budget {
+            Some(0) => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "traffic quota exceeded",
+            ))),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// caps a transfer to the budget left
+    fn limit(&self, size: usize) -> usize {
+        match self.budget {
+            Some(budget) => size.min(budget.try_into().unwrap_or(usize::MAX)),
+            None => size,
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        if let Some(budget) = self.budget.as_mut() {
+            *budget = budget.saturating_sub(n as u64);
+        }
+    }
+}
+
+/// reserves the first budget of a connection, fails once the quota is used up
+pub async fn budget(context: &RequestContext) -> Result<Budget> {
+    let id = format!("{:016x}", fastrand::u64(..));
+    let reporter = match Reporter::new(context, id.clone()) {
+        Some(reporter) if context.user.quota.is_limited() => reporter,
+        _ => return Ok(Budget { id, bytes: None }),
+    };
+
+    match reporter.report(Usage::default(), REPORT_INTERVAL).await? {
+        Some(0) => Err(close::unauthorized("traffic quota exceeded")),
+        bytes => Ok(Budget { id, bytes }),
+    }
+}
+
+#[async_trait(?Send)]
+impl Proxy for MeteredStream {
+    async fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MeteredStream {
+    /// reports what's left and releases the reservation of the connection
+    fn drop(&mut self) {
+        let pending = self.unreported.total() > 0 || self.reporting.is_some();
+        let reporter = match self.reporter.take() {
+            Some(reporter) if pending || self.budget.is_some() => reporter,
+            _ => return,
+        };
+
+        let usage = self.unreported;
+        let reporting = self.reporting.take();
+        self.background.spawn(async move {
+            if let Some(reporting) = reporting {
+                if let Err(e) = reporting.await {
+                    crate::log!("[traffic]: failed to report usage: {e}");
+                }
+            }
+            if let Err(e) = reporter.report(usage, 0).await {
+                crate::log!("[traffic]: failed to report usage: {e}");
+            }
+        });
+    }
+}
+
+impl AsyncRead for MeteredStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        ready!(self.poll_report(cx))?;
+
+        let size = self.limit(buf.remaining());
+        let want = match self.shaper.as_mut() {
+            Some(shaper) => ready!(shaper.poll_acquire(cx, size)),
+            None => size,
+        };
+
+        // only read as much as the shaper and the budget allow
+        let mut limited = buf.take(want);
+        let poll = Pin::new(&mut self.stream).poll_read(cx, &mut limited);
+        let n = match poll {
+            Poll::Ready(Ok(())) => limited.filled().len(),
+            _ => 0,
+        };
+
+        // SAFETY: the inner stream has initialized the first `n` bytes
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        self.unreported.download = self.unreported.download.saturating_add(n as u64);
+        self.consume(n);
+        if let Some(shaper) = self.shaper.as_mut() {
+            shaper.refund(want - n);
+        }
+
+        poll
+    }
+}
+
+impl AsyncWrite for MeteredStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        ready!(self.poll_report(cx))?;
+
+        let size = self.limit(buf.len());
+        let want = match self.shaper.as_mut() {
+            Some(shaper) => ready!(shaper.poll_acquire(cx, size)),
+            None => size,
+        };
+
+        let poll = Pin::new(&mut self.stream).poll_write(cx, &buf[..want]);
+        let n = match poll {
+            Poll::Ready(Ok(n)) => n,
+            _ => 0,
+        };
+
+        self.unreported.upload = self.unreported.upload.saturating_add(n as u64);
+        self.consume(n);
+        if let Some(shaper) = self.shaper.as_mut() {
+            shaper.refund(want - n);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -1,30 +1,125 @@
+pub mod balancer;
 pub mod bepass;
 pub mod blackhole;
+pub mod chain;
+pub mod close;
+pub mod failover;
+pub mod grpc;
+pub mod meter;
 pub mod relay;
+#[cfg(test)]
+pub mod testing;
+pub mod timeout;
 pub mod trojan;
 pub mod vless;
 pub mod vmess;
 pub mod ws;
 
+use std::future::Future;
+use std::rc::Rc;
 use std::sync::Arc;
 
-use crate::config::*;
-use ws::WebSocketStream;
+use crate::config::{SecureTransport, *};
+use crate::limit::Limiter;
+use crate::traffic::Traffic;
 
 use async_trait::async_trait;
+use futures_util::future::LocalBoxFuture;
 use tokio::io::{AsyncRead, AsyncWrite};
 use worker::*;
 
-#[async_trait]
-pub trait Proxy: AsyncRead + AsyncWrite + Unpin + Send {
+#[async_trait(?Send)]
+pub trait Proxy: AsyncRead + AsyncWrite + Unpin {
     async fn process(&mut self) -> Result<()>;
+
+    /// upgrades a connection opened with `SecureTransport::StartTls` to tls
+    fn start_tls(self: Box<Self>) -> Result<Box<dyn Proxy>> {
+        Err(Error::RustError(
+            "starttls is not supported by this stream".to_string(),
+        ))
+    }
 }
 
-#[async_trait]
+#[async_trait(?Send)]
 impl Proxy for Socket {
     async fn process(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn start_tls(self: Box<Self>) -> Result<Box<dyn Proxy>> {
+        Ok(Box::new(Socket::start_tls(*self)))
+    }
+}
+
+/// opens the raw connections the outbounds talk over
+#[async_trait(?Send)]
+pub trait Connector {
+    async fn connect(&self, address: &str, port: u16) -> Result<Box<dyn Proxy>>;
+
+    /// opens a connection secured by tls, or one which can be upgraded later
+    /// in case of starttls
+    async fn connect_tls(
+        &self,
+        _address: &str,
+        _port: u16,
+        _transport: &SecureTransport,
+    ) -> Result<Box<dyn Proxy>> {
+        Err(Error::RustError(
+            "tls is not supported by this connector".to_string(),
+        ))
+    }
+
+    /// opens a websocket to the address, secured by tls unless it's off
+    async fn connect_ws(
+        &self,
+        _address: &str,
+        _port: u16,
+        _transport: &SecureTransport,
+        _ws: &WsTransport,
+        _options: &WebSocketOptions,
+    ) -> Result<Box<dyn Proxy>> {
+        Err(Error::RustError(
+            "the ws transport is not supported by this connector".to_string(),
+        ))
+    }
+}
+
+/// connects through the tcp sockets of the workers runtime
+pub struct SocketConnector;
+
+#[async_trait(?Send)]
+impl Connector for SocketConnector {
+    async fn connect(&self, address: &str, port: u16) -> Result<Box<dyn Proxy>> {
+        Ok(Box::new(Socket::builder().connect(address, port)?))
+    }
+
+    async fn connect_tls(
+        &self,
+        address: &str,
+        port: u16,
+        transport: &SecureTransport,
+    ) -> Result<Box<dyn Proxy>> {
+        let transport = match transport {
+            SecureTransport::Off => worker::SecureTransport::Off,
+            SecureTransport::On => worker::SecureTransport::On,
+            SecureTransport::StartTls => worker::SecureTransport::StartTls,
+        };
+        let socket = Socket::builder()
+            .secure_transport(transport)
+            .connect(address, port)?;
+        Ok(Box::new(socket))
+    }
+
+    async fn connect_ws(
+        &self,
+        address: &str,
+        port: u16,
+        transport: &SecureTransport,
+        ws: &WsTransport,
+        options: &WebSocketOptions,
+    ) -> Result<Box<dyn Proxy>> {
+        ws::connect(address, port, transport, ws, *options).await
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -59,106 +154,378 @@ impl Network {
     }
 }
 
-#[derive(Default)]
+/// runs work which outlives the tunnel (E.g. the last usage report), through
+/// the `wait_until` of the request when there is one
+#[derive(Clone, Default)]
+pub struct Background(Option<Rc<dyn Fn(LocalBoxFuture<'static, ()>)>>);
+
+impl Background {
+    pub fn new(wait_until: impl Fn(LocalBoxFuture<'static, ()>) + 'static) -> Self {
+        Self(Some(Rc::new(wait_until)))
+    }
+
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static) {
+        match &self.0 {
+            Some(wait_until) => wait_until(Box::pin(future)),
+            None => crate::common::spawn(future),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct RequestContext {
     pub address: String,
     pub port: u16,
     pub network: Network,
     pub inbound: Inbound,
-    pub request: Option<Request>,
+    pub user: User,
+    /// address of the client, empty if unknown
+    pub client: String,
+    pub traffic: Option<Traffic>,
+    pub limiter: Option<Limiter>,
+    pub background: Background,
+    /// flow control of the websockets opened to outbounds
+    pub websocket: WebSocketOptions,
+    /// defaults to the sockets of the workers runtime
+    pub connector: Option<Rc<dyn Connector>>,
+    /// url the client connected with, bepass takes its target from the query
+    pub url: Option<Url>,
 }
 
-unsafe impl Send for RequestContext {}
-
-impl Clone for RequestContext {
-    fn clone(&self) -> Self {
-        let port = self.port;
-        let address = self.address.clone();
-        let network = self.network.clone();
-        let inbound = self.inbound.clone();
+impl RequestContext {
+    pub fn connector(&self) -> Rc<dyn Connector> {
+        self.connector
+            .clone()
+            .unwrap_or_else(|| Rc::new(SocketConnector))
+    }
+}
 
-        Self {
-            address,
-            port,
-            network,
-            inbound,
-            // to avoid unnecessary overheads of copying:
-            // context is getting filled during processing a request
-            // so no need to clone any data here
-            request: None,
-        }
+/// rejects users which are expired or outside of their allowed time windows
+pub fn ensure_active(user: &User) -> Result<()> {
+    let now = crate::common::time::now();
+    if user.is_expired(now) {
+        return Err(close::unauthorized(format!(
+            "user {} is expired",
+            user.name
+        )));
     }
+    if !user.is_active(now) {
+        return Err(close::unauthorized(format!(
+            "user {} is not active",
+            user.name
+        )));
+    }
+
+    Ok(())
 }
 
-async fn connect_outbound(ctx: RequestContext, outbound: Outbound) -> Result<Box<dyn Proxy>> {
-    let (addr, port) = match outbound.protocol {
-        Protocol::Freedom => (&ctx.address, ctx.port),
-        _ => {
-            let address = if outbound.addresses.len() > 0 {
-                &outbound.addresses[fastrand::usize(..outbound.addresses.len())]
-            } else {
-                &ctx.address
-            };
+async fn connect_outbound(
+    ctx: RequestContext,
+    outbounds: Vec<Outbound>,
+    timeouts: &Timeouts,
+) -> Result<Box<dyn Proxy>> {
+    let budget = meter::budget(&ctx).await?;
+    let lease = crate::limit::Lease::acquire(&ctx).await?;
 
-            (address, outbound.port)
+    // the outbounds of a balancer are tried one after another
+    let mut last_error = None;
+    for outbound in outbounds {
+        match open_outbound(&ctx, &outbound, timeouts).await {
+            Ok(stream) => {
+                let connection = balancer::Connection::open(&outbound.tag);
+                return Ok(Box::new(meter::MeteredStream::new(
+                    &ctx, stream, budget, lease, connection,
+                )));
+            }
+            Err(e) => last_error = Some(e),
         }
+    }
+
+    Err(last_error.unwrap_or(Error::RustError("no outbound".to_string())))
+}
+
+/// connects through an outbound, falling through its addresses
+pub async fn open_outbound(
+    ctx: &RequestContext,
+    outbound: &Outbound,
+    timeouts: &Timeouts,
+) -> Result<Box<dyn Proxy>> {
+    // freedom goes straight to the destination, the others fall through their addresses
+    let relayed = outbound.protocol != Protocol::Freedom;
+    let (candidates, port) = match relayed {
+        false => (vec![ctx.address.clone()], ctx.port),
+        true => (failover::candidates(outbound, ctx), outbound.port),
     };
 
-    console_log!(
-        "[{:?}] connecting to upstream {addr}:{port}",
-        outbound.protocol
-    );
+    let mut last_error = None;
+    for addr in candidates {
+        crate::log!(
+            "[{:?}] connecting to upstream {addr}:{port}",
+            outbound.protocol
+        );
 
-    let socket = Socket::builder().connect(addr, port)?;
+        let start = crate::common::time::now();
+        match timeout::connect(timeouts, open(ctx, outbound, &addr, port, timeouts)).await {
+            Ok(stream) => {
+                if relayed {
+                    failover::mark_healthy(outbound, &addr);
+                }
+                let elapsed = crate::common::time::now().saturating_sub(start);
+                balancer::record_latency(&outbound.tag, elapsed);
+                return Ok(stream);
+            }
+            Err(e) => {
+                crate::log!(
+                    "[{:?}] upstream {addr}:{port} failed: {e}",
+                    outbound.protocol
+                );
+                if relayed {
+                    failover::mark_failed(outbound, &addr);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    balancer::record_failure(&outbound.tag, timeouts);
+    Err(last_error.unwrap_or(Error::RustError("no upstream address".to_string())))
+}
 
+/// connects to a single address of the outbound and runs its handshake
+async fn open(
+    ctx: &RequestContext,
+    outbound: &Outbound,
+    addr: &str,
+    port: u16,
+    timeouts: &Timeouts,
+) -> Result<Box<dyn Proxy>> {
+    let socket = match &outbound.detour {
+        Some(hop) => {
+            let detour = chain::Detour {
+                hop: (**hop).clone(),
+                connector: ctx.connector(),
+                timeouts: *timeouts,
+                websocket: ctx.websocket,
+            };
+            detour.connect(addr, port).await?
+        }
+        None if outbound.transport == Transport::Ws => {
+            ctx.connector()
+                .connect_ws(
+                    addr,
+                    port,
+                    &outbound.secure_transport,
+                    &outbound.ws,
+                    &ctx.websocket,
+                )
+                .await?
+        }
+        None => match outbound.secure_transport {
+            SecureTransport::Off => ctx.connector().connect(addr, port).await?,
+            // the runtime takes the server name from the address
+            ref transport => match &outbound.sni {
+                Some(sni) if sni != addr => {
+                    return Err(Error::RustError(format!(
+                        "tls server name {sni} differs from the address {addr}"
+                    )))
+                }
+                _ => ctx.connector().connect_tls(addr, port, transport).await?,
+            },
+        },
+    };
     let mut stream: Box<dyn Proxy> = match outbound.protocol {
-        Protocol::Vless => Box::new(vless::outbound::VlessStream::new(ctx, outbound, socket)),
+        Protocol::Vless => Box::new(vless::outbound::VlessStream::new(
+            ctx.clone(),
+            outbound.clone(),
+            socket,
+        )),
         Protocol::RelayV1 => Box::new(relay::outbound::RelayStream::new(
-            ctx,
+            ctx.clone(),
             socket,
             relay::outbound::RelayVersion::V1,
         )),
         Protocol::RelayV2 => Box::new(relay::outbound::RelayStream::new(
-            ctx,
+            ctx.clone(),
             socket,
             relay::outbound::RelayVersion::V2,
         )),
+        Protocol::Trojan => Box::new(trojan::outbound::TrojanStream::new(
+            ctx.clone(),
+            outbound.clone(),
+            socket,
+        )),
         Protocol::Blackhole => Box::new(blackhole::outbound::BlackholeStream),
-        _ => Box::new(socket),
+        Protocol::Freedom => socket,
+        ref protocol => {
+            return Err(Error::RustError(format!(
+                "unsupported outbound protocol: {protocol:?}"
+            )))
+        }
     };
 
+    // the handshake of the outbound goes in the clear, the payload over tls
     stream.process().await?;
+    if outbound.secure_transport == SecureTransport::StartTls {
+        stream = stream.start_tls()?;
+    }
     Ok(stream)
 }
 
-pub async fn process(
-    config: Arc<Config>,
-    context: RequestContext,
-    ws: &WebSocket,
-    events: EventStream<'_>,
-) -> Result<()> {
-    let ws = WebSocketStream::new(events, ws);
+/// runs the inbound protocol over any transport (websocket, http stream, in-memory pipe, ...)
+pub async fn process<S>(config: Arc<Config>, context: RequestContext, stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let context = RequestContext {
+        websocket: config.websocket,
+        ..context
+    };
     match context.inbound.protocol {
         Protocol::Vmess => {
-            vmess::inbound::VmessStream::new(config, context, ws)
+            vmess::inbound::VmessStream::new(config, context, stream)
                 .process()
                 .await
         }
         Protocol::Vless => {
-            vless::inbound::VlessStream::new(config, context, ws)
+            vless::inbound::VlessStream::new(config, context, stream)
                 .process()
                 .await
         }
         Protocol::Trojan => {
-            trojan::inbound::TrojanStream::new(config, context, ws)
+            trojan::inbound::TrojanStream::new(config, context, stream)
                 .process()
                 .await
         }
         Protocol::Bepass => {
-            bepass::inbound::BepassStream::new(config, context, ws)
+            bepass::inbound::BepassStream::new(config, context, stream)
                 .process()
                 .await
         }
         _ => return Err(Error::RustError("invalid inbound protocol".to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{block_on, PipeConnector};
+    use super::*;
+
+    use sha2::{Digest, Sha224};
+
+    use futures_util::future::{self, Either};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_process_over_pipe() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+        let inbound = config.dispatch_inbound("/vless").unwrap();
+        let uuid = inbound.uuid;
+
+        let (mut client, transport) = tokio::io::duplex(1024);
+        let (upstream, mut remote) = tokio::io::duplex(1024);
+        let connector = Rc::new(PipeConnector::new(upstream));
+        let context = RequestContext {
+            inbound,
+            connector: Some(connector.clone()),
+            ..Default::default()
+        };
+
+        let client = async move {
+            let mut request = vec![0u8];
+            request.extend_from_slice(uuid.as_bytes());
+            // no addons, tcp to 1.2.3.4:80
+            request.extend_from_slice(&[0, 0x01, 0, 80, 0x01, 1, 2, 3, 4]);
+            request.extend_from_slice(b"ping");
+            client.write_all(&request).await.unwrap();
+
+            let mut response = [0u8; 6];
+            client.read_exact(&mut response).await.unwrap();
+            response
+        };
+        let remote = async move {
+            let mut buf = [0u8; 4];
+            remote.read_exact(&mut buf).await.unwrap();
+            remote.write_all(b"pong").await.unwrap();
+            (buf, remote)
+        };
+
+        let server = Box::pin(process(Arc::new(config), context, transport));
+        let peers = Box::pin(future::join(client, remote));
+        let ((response, (request, _remote)), _) = match block_on(future::select(server, peers)) {
+            Either::Right(done) => done,
+            Either::Left((result, _)) => panic!("server stopped early: {:?}", result.err()),
+        };
+
+        assert_eq!(&request, b"ping");
+        assert_eq!(&response, b"\0\0pong");
+        assert_eq!(
+            connector.target.borrow().clone(),
+            Some(("1.2.3.4".to_string(), 80))
+        );
+    }
+
+    #[test]
+    fn test_half_close() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "trojan"
+            password = "password"
+            path = "/trojan"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+        let context = RequestContext {
+            inbound: config.dispatch_inbound("/trojan").unwrap(),
+            ..Default::default()
+        };
+
+        let (mut client, transport) = tokio::io::duplex(1024);
+        let (upstream, mut remote) = tokio::io::duplex(1024);
+        let context = RequestContext {
+            connector: Some(Rc::new(PipeConnector::new(upstream))),
+            ..context
+        };
+
+        // the client sends its request and closes its writing side right away
+        let client = async move {
+            let password = crate::hex!(&crate::sha224!("password")[..]);
+            let mut request = password.into_bytes();
+            request.extend_from_slice(b"\r\n\x01\x01\x0a\x00\x00\x01\x00\x50\r\nrequest");
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            response
+        };
+        // the remote answers only after it has seen the end of the request
+        let remote = async move {
+            let mut request = Vec::new();
+            remote.read_to_end(&mut request).await.unwrap();
+            remote.write_all(b"response").await.unwrap();
+            remote.shutdown().await.unwrap();
+            request
+        };
+
+        let server = process(Arc::new(config), context, transport);
+        let (result, response, request) = block_on(future::join3(server, client, remote));
+        result.unwrap();
+        assert_eq!(request, b"request");
+        assert_eq!(response, b"response");
+    }
+}
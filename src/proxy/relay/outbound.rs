@@ -16,7 +16,7 @@ pub enum RelayVersion {
 }
 
 #[derive(Decode, Encode)]
-enum Network {
+pub enum Network {
     Tcp,
     Udp,
 }
@@ -31,21 +31,37 @@ impl Network {
 }
 
 #[derive(Decode, Encode)]
-struct Header {
+pub struct Header {
     pub ver: RelayVersion,
     pub net: Network,
     pub addr: IpAddr,
     pub port: u16,
 }
 
+/// decodes a length prefixed v2 header as written by `RelayStream`
+#[cfg(any(test, feature = "fuzzing"))]
+pub fn decode_header(buf: &[u8]) -> Result<Header> {
+    let len = match buf {
+        [a, b, ..] => u16::from_be_bytes([*a, *b]) as usize,
+        _ => return Err(Error::RustError("truncated header".to_string())),
+    };
+    let body = buf
+        .get(2..2 + len)
+        .ok_or(Error::RustError("truncated header".to_string()))?;
+
+    let (header, _) = bincode::decode_from_slice(body, bincode::config::standard())
+        .map_err(|e| Error::RustError(format!("bincode {e}")))?;
+    Ok(header)
+}
+
 pub struct RelayStream {
-    pub stream: Socket,
+    pub stream: Box<dyn Proxy>,
     context: RequestContext,
     version: RelayVersion,
 }
 
 impl RelayStream {
-    pub fn new(context: RequestContext, stream: Socket, version: RelayVersion) -> Self {
+    pub fn new(context: RequestContext, stream: Box<dyn Proxy>, version: RelayVersion) -> Self {
         Self {
             context,
             stream,
@@ -99,7 +115,7 @@ impl RelayStream {
     }
 }
 
-#[async_trait]
+#[async_trait(?Send)]
 impl Proxy for RelayStream {
     async fn process(&mut self) -> Result<()> {
         match &self.version {
@@ -107,6 +123,12 @@ impl Proxy for RelayStream {
             RelayVersion::V2 => self.process_v2().await,
         }
     }
+
+    fn start_tls(self: Box<Self>) -> Result<Box<dyn Proxy>> {
+        let mut this = *self;
+        this.stream = this.stream.start_tls()?;
+        Ok(Box::new(this))
+    }
 }
 
 impl AsyncRead for RelayStream {
@@ -128,11 +150,58 @@ impl AsyncWrite for RelayStream {
         Pin::new(&mut self.stream).poll_write(cx, buf)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// half-closes the upstream, its response can still be read afterwards
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::block_on;
+
+    use tokio::io::AsyncReadExt;
+
+    fn handshake(version: RelayVersion, network: proxy::Network) -> Vec<u8> {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let context = RequestContext {
+            address: "2001:db8::1".to_string(),
+            port: 443,
+            network,
+            ..Default::default()
+        };
+
+        block_on(async {
+            let mut stream = RelayStream::new(context, Box::new(client), version);
+            stream.process().await.unwrap();
+            drop(stream);
+
+            let mut buf = Vec::new();
+            server.read_to_end(&mut buf).await.unwrap();
+            buf
+        })
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(
+            handshake(RelayVersion::V1, proxy::Network::Tcp),
+            b"tcp@2001:db8::1$443\r\n"
+        );
+
+        let buf = handshake(RelayVersion::V2, proxy::Network::Udp);
+        let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        assert_eq!(buf.len(), len + 2);
+
+        let header = decode_header(&buf).unwrap();
+        assert!(matches!(header.ver, RelayVersion::V2));
+        assert!(matches!(header.net, Network::Udp));
+        assert_eq!(header.addr, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(header.port, 443);
     }
 }
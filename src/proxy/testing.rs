@@ -0,0 +1,77 @@
+//! helpers driving the protocol code over in-memory pipes
+
+use crate::config::User;
+use crate::proxy::{Connector, Proxy};
+
+use std::cell::RefCell;
+use std::future::Future;
+
+use async_trait::async_trait;
+use tokio::io::DuplexStream;
+use worker::*;
+
+#[async_trait(?Send)]
+impl Proxy for DuplexStream {
+    async fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// hands out one end of an in-memory pipe instead of opening a socket
+pub struct PipeConnector {
+    pub upstream: RefCell<Option<DuplexStream>>,
+    pub target: RefCell<Option<(String, u16)>>,
+}
+
+impl PipeConnector {
+    pub fn new(upstream: DuplexStream) -> Self {
+        Self {
+            upstream: RefCell::new(Some(upstream)),
+            target: RefCell::new(None),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Connector for PipeConnector {
+    async fn connect(&self, address: &str, port: u16) -> Result<Box<dyn Proxy>> {
+        self.target.replace(Some((address.to_string(), port)));
+        let upstream = self.upstream.borrow_mut().take();
+        Ok(Box::new(upstream.ok_or(Error::RustError(
+            "pipe is already taken".to_string(),
+        ))?))
+    }
+}
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+pub fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// the users of the golden vectors
+pub fn users() -> Vec<User> {
+    vec![
+        User {
+            name: "other".to_string(),
+            uuid: "a3482e88-686a-4a58-8126-99c9df64b7bf".parse().unwrap(),
+            password: "other".to_string(),
+            ..Default::default()
+        },
+        User {
+            name: "alice".to_string(),
+            uuid: "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8".parse().unwrap(),
+            password: "password".to_string(),
+            ..Default::default()
+        },
+    ]
+}
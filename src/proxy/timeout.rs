@@ -0,0 +1,158 @@
+use crate::common::time;
+use crate::config::Timeouts;
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+async fn within<T>(seconds: u64, reason: &str, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    if seconds == 0 {
+        return fut.await;
+    }
+
+    let fut = std::pin::pin!(fut);
+    match future::select(fut, time::sleep(Duration::from_secs(seconds))).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(Error::RustError(format!(
+            "{reason} timed out after {seconds}s"
+        ))),
+    }
+}
+
+/// bounds the time a client takes to send its request header
+pub async fn handshake<T>(timeouts: &Timeouts, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    within(timeouts.handshake, "handshake", fut).await
+}
+
+/// bounds connecting to the upstream, outbound handshake included
+pub async fn connect<T>(timeouts: &Timeouts, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    within(timeouts.connect, "upstream connect", fut).await
+}
+
+/// copies both directions like `tokio::io::copy_bidirectional` and gives up once
+/// neither side has moved a byte for the idle timeout
+pub async fn copy_bidirectional<A, B>(timeouts: &Timeouts, a: &mut A, b: &mut B) -> Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    if timeouts.idle == 0 {
+        tokio::io::copy_bidirectional(a, b).await?;
+        return Ok(());
+    }
+
+    // all the traffic goes through the client side, so watching it is enough
+    let last = Rc::new(Cell::new(time::now()));
+    let mut a = Activity {
+        stream: a,
+        last: last.clone(),
+    };
+    let copy = std::pin::pin!(tokio::io::copy_bidirectional(&mut a, b));
+
+    let idle = timeouts.idle * 1000;
+    let watchdog = std::pin::pin!(async {
+        loop {
+            let elapsed = time::now().saturating_sub(last.get());
+            if elapsed >= idle {
+                break;
+            }
+            time::sleep(Duration::from_millis(idle - elapsed)).await;
+        }
+    });
+
+    match future::select(copy, watchdog).await {
+        Either::Left((result, _)) => result.map(|_| ()).map_err(Error::from),
+        Either::Right(_) => Err(Error::RustError(format!(
+            "idle for {}s, closing the tunnel",
+            timeouts.idle
+        ))),
+    }
+}
+
+/// records the time of the last read or write on a stream
+struct Activity<'a, S: ?Sized> {
+    stream: &'a mut S,
+    last: Rc<Cell<u64>>,
+}
+
+impl<'a, S: AsyncRead + Unpin + ?Sized> AsyncRead for Activity<'a, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut *self.stream).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.last.set(time::now());
+        }
+        poll
+    }
+}
+
+impl<'a, S: AsyncWrite + Unpin + ?Sized> AsyncWrite for Activity<'a, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        let poll = Pin::new(&mut *self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                self.last.set(time::now());
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::block_on;
+
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_timeouts() {
+        let timeouts = Timeouts {
+            handshake: 1,
+            connect: 0,
+            idle: 1,
+        };
+
+        let pending = future::pending::<Result<()>>();
+        let e = block_on(handshake(&timeouts, pending)).unwrap_err();
+        assert_eq!(e.to_string(), "handshake timed out after 1s");
+
+        // disabled timeouts wait for the future itself
+        assert!(block_on(connect(&timeouts, async { Ok(()) })).is_ok());
+
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, _upstream) = tokio::io::duplex(64);
+        let e = block_on(async {
+            client.write_all(b"ping").await.unwrap();
+            copy_bidirectional(&timeouts, &mut a, &mut b).await
+        })
+        .unwrap_err();
+        assert_eq!(e.to_string(), "idle for 1s, closing the tunnel");
+    }
+}
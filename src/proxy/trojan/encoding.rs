@@ -1,10 +1,12 @@
-use crate::proxy::Network;
+use crate::config::User;
+use crate::proxy::{close, Network};
 
 use sha2::{Digest, Sha224};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use worker::*;
 
 pub struct Header {
+    pub user: User,
     pub network: Network,
     pub address: String,
     pub port: u16,
@@ -12,7 +14,7 @@ pub struct Header {
 
 pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
     stream: &mut S,
-    password: &str,
+    users: &[User],
 ) -> Result<Header> {
     // TODO: using BufReader instead of reading directly from the stream
 
@@ -25,17 +27,20 @@ pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
 
     let mut header_pass = [0u8; 56];
     stream.read_exact(&mut header_pass).await?;
-    {
+    let user = {
         let header_pass = String::from_utf8_lossy(&header_pass);
-        let password = {
-            let p = &crate::sha224!(&password)[..];
-            crate::hex!(p)
-        };
-
-        if password != header_pass {
-            return Err(Error::RustError("invalid password".to_string()));
-        }
-    }
+        users
+            .iter()
+            // an empty password isn't a credential, its hash is well known
+            .filter(|user| !user.password.is_empty())
+            .find(|user| {
+                let p = &crate::sha224!(&user.password)[..];
+                crate::hex!(p) == header_pass
+            })
+            .cloned()
+            .ok_or(close::unauthorized("invalid password"))?
+    };
+    crate::proxy::ensure_active(&user)?;
 
     stream.read_exact(&mut crlf).await?;
 
@@ -47,14 +52,14 @@ pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
     let network = match stream.read_u8().await? {
         0x01 => Network::Tcp,
         0x03 => Network::Udp,
-        _ => return Err(Error::RustError("invalid network type".to_string())),
+        _ => return Err(close::malformed("invalid network type")),
     };
 
     let address = match stream.read_u8().await? {
         0x01 => crate::common::parse_ipv4(stream).await?,
         0x03 => crate::common::parse_domain(stream).await?,
         0x04 => crate::common::parse_ipv6(stream).await?,
-        _ => return Err(Error::RustError("invalid address".to_string())),
+        _ => return Err(close::malformed("invalid address")),
     };
     let port = {
         let mut p = [0u8; 2];
@@ -77,8 +82,93 @@ pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
     stream.read_exact(&mut crlf).await?;
 
     Ok(Header {
+        user,
         network,
         address,
         port,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::{block_on, users};
+
+    use std::io::Cursor;
+
+    // hex(sha224("password"))
+    const PASSWORD: &str = "d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01";
+
+    fn decode(request: &[u8]) -> (Result<Header>, Vec<u8>) {
+        let mut stream = Cursor::new(request.to_vec());
+        let header = block_on(decode_request_header(&mut stream, &users()));
+        let position = stream.position() as usize;
+        (header, stream.into_inner().split_off(position))
+    }
+
+    fn request(command: &[u8]) -> Vec<u8> {
+        [PASSWORD.as_bytes(), b"\r\n", command, b"\r\n"].concat()
+    }
+
+    #[test]
+    fn test_decode_request_header() {
+        // connect to example.com:443 followed by the payload
+        let mut buf = request(b"\x01\x03\x0bexample.com\x01\xbb");
+        buf.extend_from_slice(b"GET");
+        let (header, payload) = decode(&buf);
+        let header = header.unwrap();
+        assert_eq!(header.user.name, "alice");
+        assert!(matches!(header.network, Network::Tcp));
+        assert_eq!(header.address, "example.com");
+        assert_eq!(header.port, 443);
+        assert_eq!(payload, b"GET");
+
+        // udp associate to 1.1.1.1:53 with the length of the first packet
+        let (header, _) = decode(&request(b"\x03\x01\x01\x01\x01\x01\x00\x35\x00\x20"));
+        let header = header.unwrap();
+        assert!(matches!(header.network, Network::Udp));
+        assert_eq!(header.address, "1.1.1.1");
+        assert_eq!(header.port, 53);
+
+        let (header, _) = decode(&request(
+            b"\x01\x04\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x50",
+        ));
+        assert_eq!(header.unwrap().address, "2001:db8::1");
+    }
+
+    #[test]
+    fn test_malformed_request_header() {
+        let valid = request(b"\x01\x01\x0a\x00\x00\x01\x00\x50");
+        assert!(decode(&valid).0.is_ok());
+
+        // wrong password
+        let mut wrong = valid.clone();
+        wrong[0] = b'0';
+        assert!(decode(&wrong).0.is_err());
+        // unknown command and address type
+        assert!(decode(&request(b"\x02\x01\x0a\x00\x00\x01\x00\x50"))
+            .0
+            .is_err());
+        assert!(decode(&request(b"\x01\x05\x0a\x00\x00\x01\x00\x50"))
+            .0
+            .is_err());
+        for end in 0..valid.len() {
+            assert!(decode(&valid[..end]).0.is_err(), "truncated at {end}");
+        }
+    }
+
+    #[test]
+    fn test_empty_password() {
+        // a user without a password doesn't let in the hash of an empty one
+        let mut users = users();
+        users[0].password = String::new();
+        let empty = crate::hex!(&crate::sha224!("")[..]);
+        let request = [
+            empty.as_bytes(),
+            b"\r\n\x01\x01\x0a\x00\x00\x01\x00\x50\r\n",
+        ]
+        .concat();
+        let header = block_on(decode_request_header(&mut Cursor::new(request), &users));
+        assert!(header.is_err());
+    }
+}
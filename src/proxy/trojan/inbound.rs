@@ -1,5 +1,5 @@
 use crate::config::Config;
-use crate::proxy::{trojan::encoding, ws::WebSocketStream, Proxy, RequestContext};
+use crate::proxy::{timeout, trojan::encoding, Proxy, RequestContext};
 
 use std::pin::Pin;
 use std::sync::Arc;
@@ -9,72 +9,81 @@ use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use worker::*;
 
-pub struct TrojanStream<'a> {
+pub struct TrojanStream<S> {
     pub config: Arc<Config>,
     pub context: RequestContext,
-    pub ws: WebSocketStream<'a>,
+    pub stream: S,
 }
 
-unsafe impl<'a> Send for TrojanStream<'a> {}
-
-impl<'a> TrojanStream<'a> {
-    pub fn new(config: Arc<Config>, context: RequestContext, ws: WebSocketStream<'a>) -> Self {
+impl<S> TrojanStream<S> {
+    pub fn new(config: Arc<Config>, context: RequestContext, stream: S) -> Self {
         Self {
             config,
             context,
-            ws,
+            stream,
         }
     }
 }
 
-#[async_trait]
-impl<'a> Proxy for TrojanStream<'a> {
+#[async_trait(?Send)]
+impl<S: AsyncRead + AsyncWrite + Unpin> Proxy for TrojanStream<S> {
     async fn process(&mut self) -> Result<()> {
-        let password = self.context.inbound.password.clone();
-        let header = encoding::decode_request_header(&mut self, &password).await?;
+        let users = self.context.inbound.users();
+        let timeouts = self.config.timeouts;
+        let header = timeout::handshake(
+            &timeouts,
+            encoding::decode_request_header(&mut self, &users),
+        )
+        .await?;
 
         let mut context = self.context.clone();
         {
             context.address = header.address;
             context.port = header.port;
             context.network = header.network;
+            context.user = header.user;
         }
 
-        let outbound = self.config.dispatch_outbound(&context);
-        let mut upstream = crate::proxy::connect_outbound(context, outbound).await?;
+        let outbounds = self.config.dispatch_outbound(&context)?;
+        let mut upstream = crate::proxy::connect_outbound(context, outbounds, &timeouts).await?;
 
-        tokio::io::copy_bidirectional(self, &mut upstream).await?;
+        timeout::copy_bidirectional(&timeouts, self, &mut upstream).await?;
 
         Ok(())
     }
 }
 
-impl<'a> AsyncRead for TrojanStream<'a> {
+impl<S: AsyncRead + Unpin> AsyncRead for TrojanStream<S> {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        let mut pinned = std::pin::pin!(&mut self.ws);
+        let mut pinned = std::pin::pin!(&mut self.stream);
         pinned.as_mut().poll_read(cx, buf)
     }
 }
 
-impl<'a> AsyncWrite for TrojanStream<'a> {
+impl<S: AsyncWrite + Unpin> AsyncWrite for TrojanStream<S> {
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        let mut pinned = std::pin::pin!(&mut self.ws);
+        let mut pinned = std::pin::pin!(&mut self.stream);
         pinned.as_mut().poll_write(cx, buf)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let mut pinned = std::pin::pin!(&mut self.stream);
+        pinned.as_mut().poll_flush(cx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        let mut pinned = std::pin::pin!(&mut self.stream);
+        pinned.as_mut().poll_shutdown(cx)
     }
 }
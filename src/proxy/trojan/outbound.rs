@@ -0,0 +1,134 @@
+use crate::common::encode_addr;
+use crate::config::Outbound;
+use crate::proxy::{Network, Proxy, RequestContext};
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use worker::*;
+
+pub struct TrojanStream {
+    pub stream: Box<dyn Proxy>,
+    pub outbound: Outbound,
+    context: RequestContext,
+}
+
+impl TrojanStream {
+    pub fn new(context: RequestContext, outbound: Outbound, stream: Box<dyn Proxy>) -> Self {
+        Self {
+            context,
+            outbound,
+            stream,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Proxy for TrojanStream {
+    async fn process(&mut self) -> Result<()> {
+        // udp packets are framed one by one, which isn't supported yet
+        if let Network::Udp = self.context.network {
+            return Err(Error::RustError(
+                "udp over trojan outbounds is not supported".to_string(),
+            ));
+        }
+
+        let password = &crate::sha224!(&self.outbound.password)[..];
+        let mut cmd = crate::hex!(password).into_bytes();
+        cmd.extend_from_slice(b"\r\n");
+        cmd.push(0x01);
+        match encode_addr(&self.context.address) {
+            Ok(addr) => {
+                cmd.push(if addr.len() == 4 { 0x01 } else { 0x04 });
+                cmd.extend_from_slice(&addr);
+            }
+            Err(_) => {
+                let domain = self.context.address.as_bytes();
+                let len = u8::try_from(domain.len())
+                    .map_err(|_| Error::RustError("domain name is too long".to_string()))?;
+                cmd.extend_from_slice(&[0x03, len]);
+                cmd.extend_from_slice(domain);
+            }
+        }
+        cmd.extend_from_slice(&self.context.port.to_be_bytes());
+        cmd.extend_from_slice(b"\r\n");
+
+        self.stream.write_all(&cmd).await?;
+
+        Ok(())
+    }
+
+    fn start_tls(self: Box<Self>) -> Result<Box<dyn Proxy>> {
+        let mut this = *self;
+        this.stream = this.stream.start_tls()?;
+        Ok(Box::new(this))
+    }
+}
+
+impl AsyncRead for TrojanStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TrojanStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// half-closes the upstream, its response can still be read afterwards
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::{block_on, users};
+    use crate::proxy::trojan::encoding;
+
+    #[test]
+    fn test_round_trip() {
+        let user = users().pop().unwrap();
+        for address in ["1.2.3.4", "2001:db8::1", "example.com"] {
+            let (client, mut server) = tokio::io::duplex(1024);
+            let context = RequestContext {
+                address: address.to_string(),
+                port: 8443,
+                ..Default::default()
+            };
+            let outbound = Outbound {
+                password: user.password.clone(),
+                ..Default::default()
+            };
+
+            let header = block_on(async {
+                TrojanStream::new(context, outbound, Box::new(client))
+                    .process()
+                    .await
+                    .unwrap();
+                encoding::decode_request_header(&mut server, &users()).await
+            })
+            .unwrap();
+            assert_eq!(header.user.name, user.name);
+            assert_eq!(header.address, address);
+            assert_eq!(header.port, 8443);
+        }
+    }
+}
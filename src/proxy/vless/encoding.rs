@@ -1,9 +1,11 @@
-use crate::proxy::Network;
+use crate::config::User;
+use crate::proxy::{close, Network};
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use worker::*;
 
 pub struct Header {
+    pub user: User,
     pub network: Network,
     pub address: String,
     pub port: u16,
@@ -11,7 +13,7 @@ pub struct Header {
 
 pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
     stream: &mut S,
-    uuid: &[u8; 16],
+    users: &[User],
 ) -> Result<Header> {
     // https://xtls.github.io/Xray-docs-next/en/development/protocols/vless.html
     // +------------------+-----------------+---------------------------------+---------------------------------+-------------+---------+--------------+---------+
@@ -22,14 +24,18 @@ pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
 
     // version
     if stream.read_u8().await? != 0 {
-        return Err(Error::RustError("invalid request version".to_string()));
+        return Err(close::malformed("invalid request version"));
     }
 
     let mut id = [0u8; 16];
     stream.read_exact(&mut id).await?;
-    if &id != uuid {
-        return Err(Error::RustError("incorrect request user id".to_string()));
-    }
+    let user = users
+        .iter()
+        // a nil uuid isn't a credential, it would let in anyone sending zeros
+        .find(|user| !user.uuid.is_nil() && user.uuid.as_bytes() == &id)
+        .cloned()
+        .ok_or(close::unauthorized("incorrect request user id"))?;
+    crate::proxy::ensure_active(&user)?;
 
     // Addons (ignore for now)
     let len = stream.read_u8().await?;
@@ -47,12 +53,90 @@ pub async fn decode_request_header<S: AsyncRead + AsyncWrite + Unpin>(
         0x01 => crate::common::parse_ipv4(stream).await?,
         0x02 => crate::common::parse_domain(stream).await?,
         0x03 => crate::common::parse_ipv6(stream).await?,
-        _ => return Err(Error::RustError("invalid address".to_string())),
+        _ => return Err(close::malformed("invalid address")),
     };
 
     Ok(Header {
+        user,
         network,
         address,
         port,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::{block_on, unhex, users};
+
+    use std::io::Cursor;
+
+    fn decode(hex: &str) -> (Result<Header>, Vec<u8>) {
+        let mut stream = Cursor::new(unhex(hex));
+        let header = block_on(decode_request_header(&mut stream, &users()));
+        let position = stream.position() as usize;
+        (header, stream.into_inner().split_off(position))
+    }
+
+    // the requests are put together by hand following the layout of the
+    // VLESS spec, not captured from a client.
+    // TODO: replace with requests captured from xray and sing-box clients,
+    // noting the client and its version
+    #[test]
+    fn test_decode_request_header() {
+        // version, uuid, no addons, tcp, port 443, domain example.com, payload "GET"
+        let (header, payload) = decode(
+            "00 0fbf4f812598 4b6aa6230ead4cb9efa8 00 01 01bb 02 0b6578616d706c652e636f6d 474554"
+                .replace(' ', "")
+                .as_str(),
+        );
+        let header = header.unwrap();
+        assert_eq!(header.user.name, "alice");
+        assert!(matches!(header.network, Network::Tcp));
+        assert_eq!(header.address, "example.com");
+        assert_eq!(header.port, 443);
+        assert_eq!(payload, b"GET");
+
+        // addons are skipped, udp to 8.8.8.8:53
+        let (header, _) = decode("000fbf4f8125984b6aa6230ead4cb9efa8020a000200350108080808");
+        let header = header.unwrap();
+        assert!(matches!(header.network, Network::Udp));
+        assert_eq!(header.address, "8.8.8.8");
+        assert_eq!(header.port, 53);
+
+        // ipv6 2001:db8::1 port 80
+        let (header, _) = decode(
+            "000fbf4f8125984b6aa6230ead4cb9efa80001005003\
+             20010db8000000000000000000000001",
+        );
+        assert_eq!(header.unwrap().address, "2001:db8::1");
+    }
+
+    #[test]
+    fn test_malformed_request_header() {
+        let valid = "000fbf4f8125984b6aa6230ead4cb9efa800010050010a000001";
+        assert!(decode(valid).0.is_ok());
+
+        // bad version
+        assert!(decode(&format!("01{}", &valid[2..])).0.is_err());
+        // unknown uuid
+        assert!(decode(&valid.replace("0fbf4f81", "0fbf4f82")).0.is_err());
+        // unknown command and address type
+        assert!(decode(&valid.replace("00010050", "00030050")).0.is_err());
+        assert!(decode(&valid.replace("0050010a", "0050070a")).0.is_err());
+        // truncated at every position
+        for end in (0..valid.len()).step_by(2) {
+            assert!(decode(&valid[..end]).0.is_err(), "truncated at {end}");
+        }
+    }
+
+    #[test]
+    fn test_nil_uuid() {
+        // a user without a uuid doesn't let in an all-zero id
+        let mut users = users();
+        users[0].uuid = uuid::Uuid::nil();
+        let request = unhex("00000000000000000000000000000000000000010050010a000001");
+        let header = block_on(decode_request_header(&mut Cursor::new(request), &users));
+        assert!(header.is_err());
+    }
+}
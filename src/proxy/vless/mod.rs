@@ -1,3 +1,3 @@
-mod encoding;
+pub mod encoding;
 pub mod inbound;
 pub mod outbound;
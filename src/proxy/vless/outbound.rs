@@ -12,7 +12,7 @@ use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use worker::*;
 
 pub struct VlessStream {
-    pub stream: Socket,
+    pub stream: Box<dyn Proxy>,
     pub buffer: BytesMut,
     pub outbound: Outbound,
     context: RequestContext,
@@ -20,7 +20,7 @@ pub struct VlessStream {
 }
 
 impl VlessStream {
-    pub fn new(context: RequestContext, outbound: Outbound, stream: Socket) -> Self {
+    pub fn new(context: RequestContext, outbound: Outbound, stream: Box<dyn Proxy>) -> Self {
         let buffer = BytesMut::new();
 
         Self {
@@ -33,7 +33,7 @@ impl VlessStream {
     }
 }
 
-#[async_trait]
+#[async_trait(?Send)]
 impl Proxy for VlessStream {
     async fn process(&mut self) -> Result<()> {
         let mut cmd = vec![0x00u8];
@@ -42,18 +42,31 @@ impl Proxy for VlessStream {
         cmd.extend_from_slice(&[self.context.network.to_byte()]);
 
         cmd.extend_from_slice(&self.context.port.to_be_bytes());
-        let addr = encode_addr(&self.context.address)?;
-        if addr.len() > 4 {
-            cmd.extend_from_slice(&[0x02]);
-        } else {
-            cmd.extend_from_slice(&[0x01]);
+        match encode_addr(&self.context.address) {
+            Ok(addr) => {
+                cmd.push(if addr.len() == 4 { 0x01 } else { 0x03 });
+                cmd.extend_from_slice(&addr);
+            }
+            // domain names are prefixed by their length
+            Err(_) => {
+                let domain = self.context.address.as_bytes();
+                let len = u8::try_from(domain.len())
+                    .map_err(|_| Error::RustError("domain name is too long".to_string()))?;
+                cmd.extend_from_slice(&[0x02, len]);
+                cmd.extend_from_slice(domain);
+            }
         }
-        cmd.extend_from_slice(&addr);
 
         self.stream.write_all(&cmd).await?;
 
         Ok(())
     }
+
+    fn start_tls(self: Box<Self>) -> Result<Box<dyn Proxy>> {
+        let mut this = *self;
+        this.stream = this.stream.start_tls()?;
+        Ok(Box::new(this))
+    }
 }
 
 impl AsyncRead for VlessStream {
@@ -97,11 +110,50 @@ impl AsyncWrite for VlessStream {
         Pin::new(&mut self.stream).poll_write(cx, buf)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+    /// half-closes the upstream, its response can still be read afterwards
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::{block_on, users};
+    use crate::proxy::{vless::encoding, Network};
+
+    #[test]
+    fn test_round_trip() {
+        let user = users().pop().unwrap();
+        for address in ["1.2.3.4", "2001:db8::1", "example.com"] {
+            let (client, mut server) = tokio::io::duplex(1024);
+            let context = RequestContext {
+                address: address.to_string(),
+                port: 8443,
+                network: Network::Udp,
+                ..Default::default()
+            };
+            let outbound = Outbound {
+                uuid: user.uuid,
+                ..Default::default()
+            };
+
+            let header = block_on(async {
+                VlessStream::new(context, outbound, Box::new(client))
+                    .process()
+                    .await
+                    .unwrap();
+                encoding::decode_request_header(&mut server, &users()).await
+            })
+            .unwrap();
+            assert_eq!(header.user.name, user.name);
+            assert_eq!(header.address, address);
+            assert_eq!(header.port, 8443);
+            assert!(matches!(header.network, Network::Udp));
+        }
     }
 }
@@ -1,10 +1,17 @@
+use crate::config::{SecureTransport, TextPolicy, WebSocketOptions, WsTransport};
+use crate::proxy::{close, Proxy};
+
+use std::future::Future;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use bytes::{BufMut, BytesMut};
 use futures_util::Stream;
 use pin_project_lite::pin_project;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf};
 use worker::*;
 
 pin_project! {
@@ -13,17 +20,32 @@ pin_project! {
         pub events: EventStream<'a>,
         pub ws: &'a WebSocket,
         pub buffer: BytesMut,
+        options: WebSocketOptions,
+        drain: Option<Delay>,
+        closed: bool,
     }
 }
 
+// the runtime has no event for a drained send queue, so it gets polled
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
 impl<'a> WebSocketStream<'a> {
-    pub fn new(events: EventStream<'a>, ws: &'a WebSocket) -> Self {
+    pub fn new(events: EventStream<'a>, ws: &'a WebSocket, options: WebSocketOptions) -> Self {
         Self {
             events,
             ws,
             buffer: BytesMut::new(),
+            options,
+            drain: None,
+            closed: false,
         }
     }
+
+    /// bytes queued by the runtime but not sent yet. workerd doesn't implement
+    /// it and always reports zero, so there the send queue isn't bounded
+    fn buffered_amount(&self) -> usize {
+        web_sys::WebSocket::buffered_amount(self.ws.as_ref()) as usize
+    }
 }
 
 impl<'a> AsyncRead for WebSocketStream<'a> {
@@ -41,36 +63,248 @@ impl<'a> AsyncRead for WebSocketStream<'a> {
                 return Poll::Ready(Ok(()));
             }
 
-            match this.events.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
-                    msg.bytes().iter().for_each(|x| this.buffer.put_slice(&x));
+            match ready!(this.events.as_mut().poll_next(cx)) {
+                Some(Ok(WebsocketEvent::Message(msg))) => match (msg.bytes(), this.options.text) {
+                    (Some(bytes), _) => this.buffer.put_slice(&bytes),
+                    (None, TextPolicy::Bytes) => this
+                        .buffer
+                        .put_slice(msg.text().unwrap_or_default().as_bytes()),
+                    (None, TextPolicy::Reject) => return Poll::Ready(Err(close::text_error())),
+                },
+                Some(Ok(WebsocketEvent::Close(event))) if !close::is_clean(event.code()) => {
+                    return Poll::Ready(Err(close::error(event.code(), &event.reason())))
                 }
-                Poll::Pending => return Poll::Pending,
-                _ => return Poll::Ready(Ok(())),
+                Some(Err(e)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        e.to_string(),
+                    )))
+                }
+                // clean closes and the end of the events are a regular eof
+                Some(Ok(WebsocketEvent::Close(_))) | None => return Poll::Ready(Ok(())),
             }
         }
     }
 }
 
 impl<'a> AsyncWrite for WebSocketStream<'a> {
+    /// writes at most one frame, waiting first while the send queue is above
+    /// the high-water mark
     fn poll_write(
-        self: Pin<&mut Self>,
-        _: &mut Context<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        return Poll::Ready(
+        let high_water_mark = self.options.high_water_mark;
+        while high_water_mark > 0 && self.buffered_amount() >= high_water_mark {
+            let drain = self
+                .drain
+                .get_or_insert_with(|| Delay::from(DRAIN_INTERVAL));
+            ready!(Pin::new(drain).poll(cx));
+            self.drain = None;
+        }
+
+        let size = match self.options.max_frame_size {
+            0 => buf.len(),
+            max => std::cmp::min(buf.len(), max),
+        };
+        Poll::Ready(
             self.ws
-                .send_with_bytes(buf)
-                .map(|_| buf.len())
+                .send_with_bytes(&buf[..size])
+                .map(|_| size)
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-        );
+        )
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
         Poll::Ready(Ok(()))
     }
 
+    /// websockets can't be half-closed, so the whole socket gets closed once the
+    /// upstream is done. the client acknowledges it with its own close frame
+    /// which ends the reading side as well
     fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        unimplemented!()
+        let this = self.project();
+        if !*this.closed {
+            *this.closed = true;
+            this.ws
+                .close(Some(close::NORMAL), Some("upstream closed"))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// websocket opened to an upstream server. the socket and its events are owned
+/// by a background task which pumps them into a pipe, so the outbound
+/// encoders can run over it like over any other socket
+pub struct WebSocketUpstream {
+    pipe: DuplexStream,
+}
+
+#[async_trait(?Send)]
+impl Proxy for WebSocketUpstream {
+    async fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for WebSocketUpstream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WebSocketUpstream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        Pin::new(&mut self.pipe).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.pipe).poll_shutdown(cx)
+    }
+}
+
+/// url and headers of the upgrade request to an upstream
+fn upgrade_request(
+    address: &str,
+    port: u16,
+    transport: &SecureTransport,
+    ws: &WsTransport,
+    early_data: &[u8],
+) -> (String, Vec<(String, String)>) {
+    let scheme = match transport {
+        SecureTransport::Off => "http",
+        _ => "https",
+    };
+    let host = match address.contains(':') {
+        true => format!("[{address}]"),
+        false => address.to_string(),
+    };
+    let url = format!("{scheme}://{host}:{port}{}", ws.path);
+
+    let mut headers = vec![("Upgrade".to_string(), "websocket".to_string())];
+    if let Some(host) = &ws.host {
+        headers.push(("Host".to_string(), host.clone()));
+    }
+    headers.extend(ws.headers.clone());
+    if !early_data.is_empty() {
+        headers.push((
+            ws.early_data_header.clone(),
+            URL_SAFE_NO_PAD.encode(early_data),
+        ));
+    }
+    (url, headers)
+}
+
+async fn upgrade(
+    address: &str,
+    port: u16,
+    transport: &SecureTransport,
+    ws: &WsTransport,
+    early_data: &[u8],
+) -> Result<WebSocket> {
+    let (url, fields) = upgrade_request(address, port, transport, ws, early_data);
+    let mut headers = Headers::new();
+    for (name, value) in &fields {
+        headers.set(name, value)?;
+    }
+    let request = Request::new_with_init(&url, RequestInit::new().with_headers(headers))?;
+
+    let response = Fetch::Request(request).send().await?;
+    let status = response.status_code();
+    let ws = response.websocket().ok_or(Error::RustError(format!(
+        "upstream {url} refused the websocket upgrade with status {status}"
+    )))?;
+    ws.accept()?;
+    Ok(ws)
+}
+
+/// opens a websocket to the upstream. with early data the upgrade waits for
+/// the first write, whose leading bytes go in a header of the request. its
+/// failures then only show up as the end of the stream
+pub async fn connect(
+    address: &str,
+    port: u16,
+    transport: &SecureTransport,
+    ws: &WsTransport,
+    options: WebSocketOptions,
+) -> Result<Box<dyn Proxy>> {
+    let (pipe, mut far) = tokio::io::duplex(options.max_frame_size.max(64 * 1024));
+    let socket = match ws.early_data {
+        0 => Some(upgrade(address, port, transport, ws, &[]).await?),
+        _ => None,
+    };
+
+    let (address, transport, ws) = (address.to_string(), *transport, ws.clone());
+    crate::common::spawn(async move {
+        let result = async {
+            let socket = match socket {
+                Some(socket) => socket,
+                None => {
+                    let mut early_data = vec![0u8; ws.early_data];
+                    let size = far.read(&mut early_data).await?;
+                    upgrade(&address, port, &transport, &ws, &early_data[..size]).await?
+                }
+            };
+            let events = socket.events()?;
+            let mut stream = WebSocketStream::new(events, &socket, options);
+            tokio::io::copy_bidirectional(&mut stream, &mut far).await?;
+            Ok::<_, Error>(())
+        };
+        if let Err(e) = result.await {
+            crate::log!("[ws] upstream {address}:{port} failed: {e}");
+        }
+    });
+
+    Ok(Box::new(WebSocketUpstream { pipe }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_request() {
+        let ws = WsTransport {
+            path: "/ws?ed=2048".to_string(),
+            host: Some("cdn.example.com".to_string()),
+            headers: [("User-Agent".to_string(), "tunl".to_string())].into(),
+            early_data: 2048,
+            ..Default::default()
+        };
+
+        let (url, headers) =
+            upgrade_request("2001:db8::1", 443, &SecureTransport::On, &ws, b"\xfb\xff");
+        assert_eq!(url, "https://[2001:db8::1]:443/ws?ed=2048");
+        assert_eq!(
+            headers,
+            [
+                ("Upgrade", "websocket"),
+                ("Host", "cdn.example.com"),
+                ("User-Agent", "tunl"),
+                ("Sec-WebSocket-Protocol", "-_8"),
+            ]
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+
+        let (url, headers) = upgrade_request("1.2.3.4", 80, &SecureTransport::Off, &ws, &[]);
+        assert_eq!(url, "http://1.2.3.4:80/ws?ed=2048");
+        assert_eq!(headers.len(), 3);
     }
 }
@@ -0,0 +1,94 @@
+use crate::link::Link;
+
+use qrcodegen::{QrCode, QrCodeEcc};
+use worker::*;
+
+/// modules of white space around the code as required by the spec
+const QUIET_ZONE: i32 = 4;
+
+fn encode(text: &str) -> Result<QrCode> {
+    QrCode::encode_text(text, QrCodeEcc::Medium)
+        .map_err(|_| Error::RustError("link is too long for a qr code".to_string()))
+}
+
+/// renders the text as an svg qr code, every dark module is a unit square
+pub fn svg(text: &str) -> Result<String> {
+    let qr = encode(text)?;
+    let size = qr.size() + QUIET_ZONE * 2;
+
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+            }
+        }
+    }
+
+    Ok(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\" \
+         shape-rendering=\"crispEdges\">\
+         <rect width=\"100%\" height=\"100%\" fill=\"#fff\"/>\
+         <path d=\"{path}\" fill=\"#000\"/></svg>"
+    ))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// renders a page listing every link along with its qr code
+pub fn html(link: &Link) -> Result<String> {
+    let mut items = String::new();
+    for uri in &link.links {
+        items.push_str(&format!(
+            "<li><figure>{}<figcaption><code>{}</code></figcaption></figure></li>",
+            svg(uri)?,
+            escape(uri)
+        ));
+    }
+
+    Ok(format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>tunl</title><style>\
+         ul{{list-style:none;padding:0}}li{{margin:2em auto;max-width:320px}}\
+         code{{word-break:break-all;font-size:small}}\
+         </style></head><body><ul>{items}</ul></body></html>"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_svg() {
+        // version 1 codes are 21 modules wide
+        let qr = encode("tunl").unwrap();
+        assert_eq!(qr.size(), 21);
+        // corners of the finder patterns
+        assert!(qr.get_module(0, 0) && qr.get_module(20, 0) && qr.get_module(0, 20));
+        assert!(!qr.get_module(7, 7));
+
+        let svg = svg("tunl").unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains("viewBox=\"0 0 29 29\""));
+        assert!(svg.contains("M4,4h1v1h-1z"));
+
+        assert!(encode(&"a".repeat(4000)).is_err());
+    }
+
+    #[test]
+    fn test_html() {
+        let link = Link {
+            links: vec!["trojan://a@b:443?type=ws&path=/x#<tunl>".to_string()],
+        };
+        let html = html(&link).unwrap();
+        assert_eq!(html.matches("<svg").count(), 1);
+        assert!(html.contains("type=ws&amp;path=/x#&lt;tunl&gt;"));
+    }
+}
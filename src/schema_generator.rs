@@ -1,9 +1,16 @@
 #![allow(unused)]
 
+mod admin;
 mod common;
 mod config;
+mod import;
+mod limit;
 mod link;
 mod proxy;
+mod qr;
+mod subscription;
+mod traffic;
+mod xray;
 
 use crate::config::Config;
 
@@ -0,0 +1,414 @@
+#![allow(unused)]
+
+mod admin;
+mod common;
+mod config;
+mod import;
+mod limit;
+mod link;
+mod native;
+mod proxy;
+mod qr;
+mod subscription;
+mod traffic;
+
+use crate::config::{Config, Inbound, InboundTransport};
+use crate::native::{Rewind, TcpConnector, WebSocketStream};
+use crate::proxy::RequestContext;
+
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+/// serves the websocket and httpupgrade inbounds of a config file on a plain tcp port,
+/// useful for local development and as a self-hosted relay
+///
+/// usage: server <config.toml> [listen address]
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (path, listen) = match args.as_slice() {
+        [_, path] => (path, DEFAULT_LISTEN),
+        [_, path, listen] => (path, listen.as_str()),
+        _ => {
+            eprintln!("usage: {} <config.toml> [listen address]", args[0]);
+            std::process::exit(2);
+        }
+    };
+
+    let buf = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {path}: {e}");
+        std::process::exit(1);
+    });
+    let config = Arc::new(Config::new(&buf));
+    for problem in config.validate() {
+        eprintln!("[config]: {problem}");
+    }
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+    let serve = async {
+        let listener = TcpListener::bind(listen).await?;
+        println!("listening on {}", listener.local_addr()?);
+        serve(config, listener).await
+    };
+    if let Err(e) = local.block_on(&rt, serve) {
+        eprintln!("[server]: {e}");
+        std::process::exit(1);
+    }
+}
+
+async fn serve(config: Arc<Config>, listener: TcpListener) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let config = config.clone();
+        tokio::task::spawn_local(async move {
+            if let Err(e) = tunnel(config, stream, peer).await {
+                println!("[tunnel] {peer}: {e}");
+            }
+        });
+    }
+}
+
+async fn tunnel(
+    config: Arc<Config>,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+) -> worker::Result<()> {
+    stream.set_nodelay(true)?;
+
+    // httpupgrade clients send the raw stream right after the upgrade
+    let (head, len) = native::read_head(&mut stream).await?;
+    let upgrade = native::request_path(&head)
+        .and_then(|path| config.dispatch_inbound(path))
+        .filter(|inbound| inbound.transport == InboundTransport::HttpUpgrade);
+    if let Some(inbound) = upgrade {
+        return http_upgrade(config, inbound, &head, len, stream, peer).await;
+    }
+    let url = native::request_url(&head[..len]);
+    let stream = Rewind::new(head, stream);
+
+    // the inbound is picked by the path of the websocket upgrade request, the
+    // splithttp and grpc ones are only served by the worker
+    let mut inbound = None;
+    let callback = |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
+        inbound = config
+            .dispatch_inbound(req.uri().path())
+            .filter(|inbound| inbound.transport == InboundTransport::Ws);
+        match inbound {
+            Some(_) => Ok(res),
+            None => {
+                let mut res = ErrorResponse::new(None);
+                *res.status_mut() = StatusCode::NOT_FOUND;
+                Err(res)
+            }
+        }
+    };
+
+    let ws = tokio_tungstenite::accept_hdr_async(stream, callback)
+        .await
+        .map_err(|e| worker::Error::RustError(e.to_string()))?;
+    let context = RequestContext {
+        inbound: inbound.unwrap_or_default(),
+        client: peer.ip().to_string(),
+        connector: Some(Rc::new(TcpConnector)),
+        url,
+        ..Default::default()
+    };
+
+    let mut stream = WebSocketStream::new(ws, config.websocket);
+    let result = proxy::process(config, context, &mut stream).await;
+    if let Err(e) = &result {
+        let (code, reason) = proxy::close::reason(e);
+        let _ = stream.close(code, reason).await;
+    }
+    result
+}
+
+async fn http_upgrade(
+    config: Arc<Config>,
+    inbound: Inbound,
+    head: &[u8],
+    len: usize,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+) -> worker::Result<()> {
+    let upgrade = native::header(&head[..len], "upgrade").unwrap_or_default();
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        stream
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+            .await?;
+        return Err(worker::Error::RustError(format!(
+            "invalid upgrade header {upgrade:?}"
+        )));
+    }
+    stream
+        .write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+        )
+        .await?;
+
+    let context = RequestContext {
+        inbound,
+        client: peer.ip().to_string(),
+        connector: Some(Rc::new(TcpConnector)),
+        url: native::request_url(&head[..len]),
+        ..Default::default()
+    };
+    let stream = Rewind::new(head[len..].to_vec(), stream);
+    proxy::process(config, context, stream).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn test_vless_over_websocket() {
+        vless_over_websocket(4);
+        // the response header is split over frames instead of being cut short
+        vless_over_websocket(1);
+    }
+
+    fn vless_over_websocket(max_frame_size: usize) {
+        let config = Arc::new(Config::new(&format!(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+
+            [websocket]
+            max_frame_size = {max_frame_size}
+            "#,
+        )));
+        let uuid = config.inbound[0].uuid;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&rt, async move {
+            // upstream answering a single request
+            let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let echo_port = echo.local_addr().unwrap().port();
+            tokio::task::spawn_local(async move {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::task::spawn_local(serve(config, listener));
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let url = format!("ws://{addr}/unknown");
+            assert!(tokio_tungstenite::client_async(url, stream).await.is_err());
+
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let url = format!("ws://{addr}/vless");
+            let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+            let mut request = vec![0u8];
+            request.extend_from_slice(uuid.as_bytes());
+            request.extend_from_slice(&[0, 0x01]);
+            request.extend_from_slice(&echo_port.to_be_bytes());
+            request.extend_from_slice(&[0x01, 127, 0, 0, 1]);
+            request.extend_from_slice(b"ping");
+            ws.send(Message::Binary(request)).await.unwrap();
+
+            let mut response = Vec::new();
+            while response.len() < 6 {
+                match ws.next().await {
+                    Some(Ok(Message::Binary(data))) => {
+                        assert!(data.len() <= max_frame_size);
+                        response.extend(data)
+                    }
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+            assert_eq!(response, b"\0\0ping");
+
+            // the upstream closing is forwarded as a normal close frame
+            match ws.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1000),
+                other => panic!("unexpected message: {other:?}"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_bepass_over_websocket() {
+        let config = Arc::new(Config::new(
+            r#"
+            [[inbound]]
+            protocol = "bepass"
+            path = "/bepass"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        ));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&rt, async move {
+            let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let echo_port = echo.local_addr().unwrap().port();
+            tokio::task::spawn_local(async move {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::task::spawn_local(serve(config, listener));
+
+            // the target is taken from the query of the upgrade request
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let url = format!("ws://{addr}/bepass?host=127.0.0.1&port={echo_port}&net=tcp");
+            let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+            ws.send(Message::Binary(b"ping".to_vec())).await.unwrap();
+
+            match ws.next().await {
+                Some(Ok(Message::Binary(data))) => assert_eq!(data, b"ping"),
+                other => panic!("unexpected message: {other:?}"),
+            }
+        });
+    }
+
+    #[test]
+    fn test_http_upgrade() {
+        let config = Arc::new(Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+            transport = "httpupgrade"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        ));
+        let uuid = config.inbound[0].uuid;
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&rt, async move {
+            let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let echo_port = echo.local_addr().unwrap().port();
+            tokio::task::spawn_local(async move {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::task::spawn_local(serve(config, listener));
+
+            // the vless request follows the upgrade request right away
+            let mut request = b"GET /vless?ed=2048 HTTP/1.1\r\nHost: tunl\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n".to_vec();
+            request.push(0);
+            request.extend_from_slice(uuid.as_bytes());
+            request.extend_from_slice(&[0, 0x01]);
+            request.extend_from_slice(&echo_port.to_be_bytes());
+            request.extend_from_slice(&[0x01, 127, 0, 0, 1]);
+            request.extend_from_slice(b"ping");
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(&request).await.unwrap();
+            let (head, len) = native::read_head(&mut stream).await.unwrap();
+            assert!(head.starts_with(b"HTTP/1.1 101 "));
+
+            let mut response = head[len..].to_vec();
+            stream.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"\0\0ping");
+        });
+    }
+
+    #[test]
+    fn test_close_codes() {
+        let config = Arc::new(Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        ));
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local = tokio::task::LocalSet::new();
+        local.block_on(&rt, async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::task::spawn_local(serve(config, listener));
+
+            let unknown_user = {
+                let mut request = vec![0u8; 17];
+                request.extend_from_slice(&[0, 0x01, 0, 80, 0x01, 127, 0, 0, 1]);
+                Message::Binary(request)
+            };
+            let cases = [
+                (unknown_user, proxy::close::POLICY_VIOLATION),
+                (
+                    Message::Text("hello".into()),
+                    proxy::close::UNSUPPORTED_DATA,
+                ),
+            ];
+            for (message, code) in cases {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let url = format!("ws://{addr}/vless");
+                let (mut ws, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+                ws.send(message).await.unwrap();
+
+                match ws.next().await {
+                    Some(Ok(Message::Close(Some(frame)))) => {
+                        assert_eq!(u16::from(frame.code), code)
+                    }
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,499 @@
+use crate::config::{Config, Inbound, InboundTransport, SplitHttpOptions};
+use crate::proxy::{self, RequestContext};
+use crate::{admin, limit, traffic};
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use bytes::BytesMut;
+use futures_util::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+const DURABLE_OBJECT_BINDING: &str = "SESSIONS";
+
+// bytes of the download which may wait for the client before writes block
+const DOWNLOAD_BUFFER: usize = 64 * 1024;
+
+/// finds the splithttp inbound of a request path, along with the session id
+/// and the sequence number of uploads (E.g. /vless/<session>/<seq>)
+pub fn dispatch(config: &Config, path: &str) -> Option<(Inbound, String, Option<u64>)> {
+    config
+        .inbound
+        .iter()
+        .filter(|inbound| inbound.transport == InboundTransport::SplitHttp)
+        .find_map(|inbound| {
+            let rest = path
+                .strip_prefix(inbound.path.trim_end_matches('/'))?
+                .strip_prefix('/')?;
+            let (session, seq) = match rest.split_once('/') {
+                Some((session, seq)) => (session, Some(seq.parse().ok()?)),
+                None => (rest, None),
+            };
+            match session.is_empty() || session.contains('/') {
+                true => None,
+                false => Some((inbound.clone(), session.to_string(), seq)),
+            }
+        })
+}
+
+/// hands the request to the durable object of its session, so the download
+/// and the uploads meet even if they reach different isolates
+pub async fn forward(
+    req: Request,
+    env: &Env,
+    inbound: &Inbound,
+    session: &str,
+) -> Result<Response> {
+    let namespace = match env.durable_object(DURABLE_OBJECT_BINDING) {
+        Ok(namespace) => namespace,
+        Err(_) => return Response::error("splithttp sessions are not configured", 503),
+    };
+
+    let stub = namespace
+        .id_from_name(&format!("{}/{session}", inbound.path))?
+        .get_stub()?;
+    stub.fetch_with_request(req).await
+}
+
+#[derive(Default)]
+struct Shared {
+    /// uploads which arrived ahead of their turn
+    pending: BTreeMap<u64, Vec<u8>>,
+    next: u64,
+    upload: BytesMut,
+    download: BytesMut,
+    /// the tunnel is done, the download ends once it's drained
+    finished: bool,
+    /// the client went away
+    closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+    response: Option<Waker>,
+    uploaders: Vec<Waker>,
+}
+
+impl Shared {
+    fn wake(waker: &mut Option<Waker>) {
+        if let Some(waker) = waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// a tunnel made of a single download and a sequence of uploads
+pub struct Session {
+    state: RefCell<Shared>,
+    options: SplitHttpOptions,
+    started: Cell<bool>,
+}
+
+impl Session {
+    pub fn new(options: SplitHttpOptions) -> Rc<Self> {
+        Rc::new(Self {
+            state: RefCell::new(Shared::default()),
+            options,
+            started: Cell::new(false),
+        })
+    }
+
+    /// queues the body of an upload, it's passed to the tunnel once all the
+    /// uploads before it have arrived. waits while the tunnel is behind
+    pub async fn upload(&self, seq: u64, data: Vec<u8>) -> std::result::Result<(), String> {
+        if data.len() > self.options.max_post_size {
+            return Err(format!(
+                "upload of {} bytes is too large (max {})",
+                data.len(),
+                self.options.max_post_size
+            ));
+        }
+
+        {
+            let state = &mut *self.state.borrow_mut();
+            if state.closed || state.finished {
+                return Err("session is closed".to_string());
+            }
+            if seq < state.next || state.pending.contains_key(&seq) {
+                return Err(format!("upload {seq} was already received"));
+            }
+            if seq != state.next && state.pending.len() >= self.options.max_buffered_posts {
+                return Err(format!(
+                    "too many uploads ahead of {} (max {})",
+                    state.next, self.options.max_buffered_posts
+                ));
+            }
+
+            state.pending.insert(seq, data);
+            while let Some(data) = state.pending.remove(&state.next) {
+                state.upload.extend_from_slice(&data);
+                state.next += 1;
+            }
+            Shared::wake(&mut state.reader);
+        }
+
+        // the tunnel keeps at most one more upload worth of bytes unread
+        std::future::poll_fn(|cx| {
+            let mut state = self.state.borrow_mut();
+            if state.upload.len() <= self.options.max_post_size || state.closed || state.finished {
+                return Poll::Ready(());
+            }
+            state.uploaders.push(cx.waker().clone());
+            Poll::Pending
+        })
+        .await;
+        Ok(())
+    }
+
+    /// the stream the inbound protocol runs over, only handed out once
+    pub fn stream(self: &Rc<Self>) -> Option<SessionStream> {
+        match self.started.replace(true) {
+            true => None,
+            false => Some(SessionStream(self.clone())),
+        }
+    }
+
+    /// body of the download response
+    pub fn download(self: &Rc<Self>) -> Download {
+        Download(self.clone())
+    }
+
+    fn close(&self) {
+        let mut state = self.state.borrow_mut();
+        state.closed = true;
+        Shared::wake(&mut state.reader);
+        Shared::wake(&mut state.writer);
+        state.uploaders.drain(..).for_each(Waker::wake);
+    }
+}
+
+pub struct SessionStream(Rc<Session>);
+
+impl AsyncRead for SessionStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<tokio::io::Result<()>> {
+        let mut state = self.0.state.borrow_mut();
+        let size = std::cmp::min(state.upload.len(), buf.remaining());
+        if size > 0 {
+            buf.put_slice(&state.upload.split_to(size));
+            state.uploaders.drain(..).for_each(Waker::wake);
+            return Poll::Ready(Ok(()));
+        }
+
+        // the uploads have no end of their own, the tunnel ends with the download
+        if state.closed {
+            return Poll::Ready(Ok(()));
+        }
+        state.reader = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for SessionStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        let mut state = self.0.state.borrow_mut();
+        if state.closed {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        let size = std::cmp::min(
+            DOWNLOAD_BUFFER.saturating_sub(state.download.len()),
+            buf.len(),
+        );
+        if size == 0 {
+            state.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        state.download.extend_from_slice(&buf[..size]);
+        Shared::wake(&mut state.response);
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// ends the download, there is no way to tell the client about the end of
+    /// the upload only
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let mut state = self.0.state.borrow_mut();
+        state.finished = true;
+        Shared::wake(&mut state.response);
+        state.uploaders.drain(..).for_each(Waker::wake);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SessionStream {
+    fn drop(&mut self) {
+        let mut state = self.0.state.borrow_mut();
+        state.finished = true;
+        Shared::wake(&mut state.response);
+        state.uploaders.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// the download ends with the tunnel, dropping it closes the tunnel
+pub struct Download(Rc<Session>);
+
+impl Stream for Download {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.0.state.borrow_mut();
+        if !state.download.is_empty() {
+            let chunk = state.download.split().to_vec();
+            Shared::wake(&mut state.writer);
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+        state.response = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// holds a single splithttp session and runs its tunnel
+#[durable_object]
+pub struct SplitHttpSession {
+    env: Env,
+    session: Option<Rc<Session>>,
+    /// loaded by the first request of the session, the uploads reuse it
+    config: Option<Arc<Config>>,
+}
+
+#[durable_object]
+impl DurableObject for SplitHttpSession {
+    fn new(state: State, env: Env) -> Self {
+        // the macro of the durable object refers to the argument by the name
+        // `state`, so it can't be `_`. sessions keep nothing in storage
+        let _ = state;
+        Self {
+            env,
+            session: None,
+            config: None,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => {
+                let config = admin::load_config(&self.env, crate::CONFIG.clone()).await;
+                self.config.insert(config).clone()
+            }
+        };
+        let (inbound, _, seq) = match dispatch(&config, &req.path()) {
+            Some(found) => found,
+            None => return Response::error("not found", 404),
+        };
+        let session = self
+            .session
+            .get_or_insert_with(|| Session::new(config.splithttp))
+            .clone();
+
+        match (req.method(), seq) {
+            (Method::Get, None) => {
+                let stream = match session.stream() {
+                    Some(stream) => stream,
+                    None => return Response::error("session is already open", 409),
+                };
+                let context = RequestContext {
+                    inbound,
+                    client: req.headers().get("CF-Connecting-IP")?.unwrap_or_default(),
+                    traffic: traffic::Traffic::new(&self.env),
+                    limiter: limit::Limiter::new(&self.env),
+                    url: req.url().ok(),
+                    ..Default::default()
+                };
+                wasm_bindgen_futures::spawn_local(async move {
+                    if let Err(e) = proxy::process(config, context, stream).await {
+                        console_log!("[splithttp]: {}", e);
+                    }
+                });
+
+                // keeps proxies in between from buffering the download
+                let mut headers = Headers::new();
+                headers.set("content-type", "text/event-stream")?;
+                headers.set("cache-control", "no-store")?;
+                headers.set("x-accel-buffering", "no")?;
+                Ok(Response::from_stream(session.download())?.with_headers(headers))
+            }
+            (Method::Post, Some(seq)) => {
+                let data = req.bytes().await?;
+                match session.upload(seq, data).await {
+                    Ok(()) => Response::ok(""),
+                    Err(e) => Response::error(e, 400),
+                }
+            }
+            _ => Response::error("method not allowed", 405),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::testing::{block_on, PipeConnector};
+
+    use std::sync::Arc;
+
+    use futures_util::{future, StreamExt};
+    use sha2::{Digest, Sha224};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_dispatch() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+
+            [[inbound]]
+            protocol = "trojan"
+            password = "password"
+            path = "/trojan/"
+            transport = "splithttp"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+
+        let (inbound, session, seq) = dispatch(&config, "/trojan/abc/3").unwrap();
+        assert_eq!(inbound.protocol, crate::config::Protocol::Trojan);
+        assert_eq!((session.as_str(), seq), ("abc", Some(3)));
+        assert_eq!(dispatch(&config, "/trojan/abc").unwrap().2, None);
+        assert!(dispatch(&config, "/trojan/").is_none());
+        assert!(dispatch(&config, "/trojan/abc/x").is_none());
+        assert!(dispatch(&config, "/vless/abc").is_none());
+    }
+
+    #[test]
+    fn test_session() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "trojan"
+            password = "password"
+            path = "/trojan"
+            transport = "splithttp"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+        let session = Session::new(SplitHttpOptions {
+            max_buffered_posts: 2,
+            ..Default::default()
+        });
+        let (upstream, mut remote) = tokio::io::duplex(1024);
+        let context = RequestContext {
+            inbound: config.dispatch_inbound("/trojan").unwrap(),
+            connector: Some(Rc::new(PipeConnector::new(upstream))),
+            ..Default::default()
+        };
+        let stream = session.stream().unwrap();
+        assert!(session.stream().is_none());
+
+        let password = crate::hex!(&crate::sha224!("password")[..]);
+        let mut header = password.into_bytes();
+        header.extend_from_slice(b"\r\n\x01\x01\x0a\x00\x00\x01\x00\x50\r\n");
+
+        let (download, request) = block_on(async {
+            // the uploads arrive out of order
+            session.upload(2, b"quest".to_vec()).await.unwrap();
+            session.upload(1, b"re".to_vec()).await.unwrap();
+            assert!(session.upload(4, b"".to_vec()).await.is_err());
+            session.upload(0, header).await.unwrap();
+            assert!(session.upload(1, b"re".to_vec()).await.is_err());
+
+            let tunnel = proxy::process(Arc::new(config), context, stream);
+            let remote = async move {
+                let mut request = [0u8; 7];
+                remote.read_exact(&mut request).await.unwrap();
+                remote.write_all(b"response").await.unwrap();
+                remote.shutdown().await.unwrap();
+                request
+            };
+            let download = session.download().map(|chunk| chunk.unwrap()).concat();
+            let (result, request, download) = future::join3(tunnel, remote, download).await;
+            result.unwrap();
+            (download, request)
+        });
+        assert_eq!(&request, b"request");
+        assert_eq!(download, b"response");
+    }
+
+    #[test]
+    fn test_bepass_session() {
+        let config = Config::new(
+            r#"
+            [[inbound]]
+            protocol = "bepass"
+            path = "/bepass"
+            transport = "splithttp"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        );
+        let session = Session::new(SplitHttpOptions::default());
+        let (upstream, mut remote) = tokio::io::duplex(1024);
+        let connector = Rc::new(PipeConnector::new(upstream));
+        // the target comes from the query of the download request
+        let context = RequestContext {
+            inbound: config.dispatch_inbound("/bepass").unwrap(),
+            connector: Some(connector.clone()),
+            url: Url::parse("https://tunl.workers.dev/bepass/abc?host=10.0.0.1&port=80&net=tcp")
+                .ok(),
+            ..Default::default()
+        };
+        let stream = session.stream().unwrap();
+
+        let (download, request) = block_on(async {
+            session.upload(0, b"request".to_vec()).await.unwrap();
+
+            let tunnel = proxy::process(Arc::new(config), context, stream);
+            let remote = async move {
+                let mut request = [0u8; 7];
+                remote.read_exact(&mut request).await.unwrap();
+                remote.write_all(b"response").await.unwrap();
+                remote.shutdown().await.unwrap();
+                request
+            };
+            let download = session.download().map(|chunk| chunk.unwrap()).concat();
+            let (result, request, download) = future::join3(tunnel, remote, download).await;
+            result.unwrap();
+            (download, request)
+        });
+        assert_eq!(&request, b"request");
+        assert_eq!(download, b"response");
+        assert_eq!(
+            connector.target.borrow().clone(),
+            Some(("10.0.0.1".to_string(), 80))
+        );
+    }
+}
@@ -0,0 +1,506 @@
+use crate::common::{constant_time_eq, time};
+use crate::config::{Config, Inbound, InboundTransport, Protocol, User};
+use crate::link::{self, Share};
+use crate::traffic::{self, Traffic};
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde_json::{json, Value};
+use worker::*;
+
+#[derive(Debug, PartialEq)]
+pub enum Format {
+    /// base64 encoded list of share links (v2rayN and friends)
+    Base64,
+    Clash,
+    SingBox,
+    Xray,
+}
+
+impl Format {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "base64" | "v2rayn" => Some(Self::Base64),
+            "clash" | "mihomo" => Some(Self::Clash),
+            "singbox" | "sing-box" => Some(Self::SingBox),
+            "xray" | "v2ray" => Some(Self::Xray),
+            _ => None,
+        }
+    }
+
+    /// picks the format from the `format` query parameter, falls back to the user agent
+    pub fn negotiate(query: Option<&str>, user_agent: &str) -> Self {
+        if let Some(format) = query.and_then(Self::from_str) {
+            return format;
+        }
+
+        let user_agent = user_agent.to_lowercase();
+        if ["clash", "mihomo", "stash"]
+            .iter()
+            .any(|c| user_agent.contains(c))
+        {
+            Self::Clash
+        } else if ["sing-box", "sfa", "sfi", "sfm", "hiddify"]
+            .iter()
+            .any(|c| user_agent.contains(c))
+        {
+            Self::SingBox
+        } else {
+            Self::Base64
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Base64 => "text/plain; charset=utf-8",
+            Self::Clash => "text/yaml; charset=utf-8",
+            Self::SingBox | Self::Xray => "application/json",
+        }
+    }
+}
+
+pub async fn handle(req: Request, env: &Env, config: Arc<Config>) -> Result<Response> {
+    let prefix = config
+        .subscription
+        .as_ref()
+        .map(|s| s.path.len())
+        .unwrap_or(0);
+    let path = req.path();
+    let token = path[prefix..].trim_matches('/');
+
+    let users = owners(&config, token);
+    if token.is_empty() || users.is_empty() {
+        return Response::error("not found", 404);
+    }
+
+    let url = req.url()?;
+    let host = url.host().map(|x| x.to_string()).unwrap_or_default();
+    let query = url
+        .query_pairs()
+        .find(|(k, _)| k == "format")
+        .map(|(_, v)| v.to_string());
+    let user_agent = req.headers().get("User-Agent")?.unwrap_or_default();
+    let format = Format::negotiate(query.as_deref(), &user_agent);
+
+    let shares = link::shares(&config, &host, |user| user.sub_token == token);
+    let mut headers = Headers::new();
+    headers.set("Content-Type", format.content_type())?;
+    headers.set("Profile-Update-Interval", "12")?;
+    headers.set("Subscription-Userinfo", &userinfo(env, &users).await)?;
+
+    Ok(Response::ok(render(&format, &shares))?.with_headers(headers))
+}
+
+/// returns the enabled users owning the token along with their inbounds
+fn owners<'a>(config: &'a Config, token: &str) -> Vec<(&'a Inbound, User)> {
+    config
+        .inbound
+        .iter()
+        .flat_map(|inbound| {
+            inbound
+                .users()
+                .into_iter()
+                .filter(|user| !token.is_empty() && constant_time_eq(&user.sub_token, token))
+                .map(move |user| (inbound, user))
+        })
+        .collect()
+}
+
+/// builds the `Subscription-Userinfo` header out of the quotas and usage of the users
+async fn userinfo(env: &Env, users: &[(&Inbound, User)]) -> String {
+    let mut upload = 0;
+    let mut download = 0;
+    let mut total = 0;
+    let mut expire = None;
+
+    let traffic = Traffic::new(env);
+    let period = traffic::current_period();
+    for (inbound, user) in users {
+        let monthly = user.quota.monthly > 0;
+        total += if monthly {
+            user.quota.monthly
+        } else {
+            user.quota.total
+        };
+
+        if let Some(traffic) = &traffic {
+            match traffic.stats(&traffic::user_key(inbound, user)).await {
+                Ok(stats) => {
+                    let usage = match monthly {
+                        true if stats.period == period => stats.monthly,
+                        true => Default::default(),
+                        false => stats.total,
+                    };
+                    upload += usage.upload;
+                    download += usage.download;
+                }
+                Err(e) => console_log!("[subscription]: failed to load stats: {e}"),
+            }
+        }
+
+        if let Some(t) = user.expires_at.as_deref().and_then(time::parse_datetime) {
+            expire = Some(expire.map_or(t, |e: u64| e.min(t)));
+        }
+    }
+
+    let mut info = format!("upload={upload}; download={download}; total={total}");
+    if let Some(expire) = expire {
+        info.push_str(&format!("; expire={}", expire / 1000));
+    }
+    info
+}
+
+pub fn render(format: &Format, shares: &[Share]) -> String {
+    match format {
+        Format::Base64 => {
+            let links: Vec<String> = shares.iter().filter_map(Share::uri).collect();
+            STANDARD.encode(links.join("\n"))
+        }
+        Format::Clash => render_clash(shares),
+        Format::SingBox => render_singbox(shares),
+        Format::Xray => render_xray(shares),
+    }
+}
+
+/// makes the remarks unique since clients use them as identifiers
+fn names(shares: &[Share]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for share in shares {
+        let mut name = share.remark.clone();
+        let mut i = 2;
+        while names.contains(&name) {
+            name = format!("{} #{i}", share.remark);
+            i += 1;
+        }
+        names.push(name);
+    }
+    names
+}
+
+fn render_clash(shares: &[Share]) -> String {
+    let names = names(shares);
+    let proxies: Vec<(String, Value)> = shares
+        .iter()
+        .zip(names)
+        .filter_map(|(share, name)| {
+            let transport = match share.inbound.transport {
+                InboundTransport::Ws => json!({
+                    "ws-opts": {
+                        "path": share.inbound.path,
+                        "headers": { "Host": share.host },
+                    },
+                }),
+                InboundTransport::Grpc => json!({
+                    "network": "grpc",
+                    "grpc-opts": { "grpc-service-name": share.inbound.service_name() },
+                }),
+                // an upgrade without the websocket framing afterwards
+                InboundTransport::HttpUpgrade => json!({
+                    "ws-opts": {
+                        "path": share.inbound.path,
+                        "headers": { "Host": share.host },
+                        "v2ray-http-upgrade": true,
+                    },
+                }),
+                // clash has no splithttp transport
+                InboundTransport::SplitHttp => return None,
+            };
+            let mut proxy = json!({
+                "name": name,
+                "server": share.address,
+                "port": share.port,
+                "udp": true,
+                "tls": true,
+                "network": "ws",
+            });
+            merge(&mut proxy, transport);
+
+            let fields = match share.inbound.protocol {
+                Protocol::Vless => json!({
+                    "type": "vless",
+                    "uuid": share.user.uuid,
+                    "servername": share.sni,
+                }),
+                Protocol::Vmess => json!({
+                    "type": "vmess",
+                    "uuid": share.user.uuid,
+                    "alterId": 0,
+                    "cipher": "zero",
+                    "servername": share.sni,
+                }),
+                Protocol::Trojan => json!({
+                    "type": "trojan",
+                    "password": share.user.password,
+                    "sni": share.sni,
+                }),
+                _ => return None,
+            };
+            merge(&mut proxy, fields);
+            if let Some(fp) = &share.fingerprint {
+                merge(&mut proxy, json!({ "client-fingerprint": fp }));
+            }
+            if !share.alpn.is_empty() {
+                merge(&mut proxy, json!({ "alpn": share.alpn }));
+            }
+
+            Some((name, proxy))
+        })
+        .collect();
+
+    // json is a subset of yaml, so each proxy is written as a flow mapping
+    let mut yaml = String::from("proxies:\n");
+    for (_, proxy) in &proxies {
+        yaml.push_str(&format!("  - {proxy}\n"));
+    }
+
+    let names: Vec<&String> = proxies.iter().map(|(name, _)| name).collect();
+    yaml.push_str("proxy-groups:\n");
+    yaml.push_str(&format!(
+        "  - {}\n",
+        json!({ "name": "tunl", "type": "select", "proxies": names })
+    ));
+    yaml.push_str("rules:\n  - MATCH,tunl\n");
+    yaml
+}
+
+fn render_singbox(shares: &[Share]) -> String {
+    let names = names(shares);
+    let mut outbounds: Vec<Value> = shares
+        .iter()
+        .zip(names)
+        .filter_map(|(share, tag)| {
+            let transport = match share.inbound.transport {
+                InboundTransport::Ws => json!({
+                    "type": "ws",
+                    "path": share.inbound.path,
+                    "headers": { "Host": share.host },
+                }),
+                InboundTransport::Grpc => json!({
+                    "type": "grpc",
+                    "service_name": share.inbound.service_name(),
+                }),
+                InboundTransport::HttpUpgrade => json!({
+                    "type": "httpupgrade",
+                    "path": share.inbound.path,
+                    "host": share.host,
+                }),
+                // sing-box has no splithttp transport
+                InboundTransport::SplitHttp => return None,
+            };
+            let mut outbound = json!({
+                "tag": tag,
+                "server": share.address,
+                "server_port": share.port,
+                "tls": { "enabled": true, "server_name": share.sni },
+                "transport": transport,
+            });
+
+            let fields = match share.inbound.protocol {
+                Protocol::Vless => json!({ "type": "vless", "uuid": share.user.uuid }),
+                Protocol::Vmess => json!({
+                    "type": "vmess",
+                    "uuid": share.user.uuid,
+                    "security": "zero",
+                    "alter_id": 0,
+                }),
+                Protocol::Trojan => json!({ "type": "trojan", "password": share.user.password }),
+                _ => return None,
+            };
+            merge(&mut outbound, fields);
+            if let Some(fp) = &share.fingerprint {
+                merge(
+                    &mut outbound["tls"],
+                    json!({ "utls": { "enabled": true, "fingerprint": fp } }),
+                );
+            }
+            if !share.alpn.is_empty() {
+                merge(&mut outbound["tls"], json!({ "alpn": share.alpn }));
+            }
+
+            Some(outbound)
+        })
+        .collect();
+
+    let tags: Vec<Value> = outbounds.iter().map(|o| o["tag"].clone()).collect();
+    outbounds.insert(
+        0,
+        json!({ "type": "selector", "tag": "tunl", "outbounds": tags }),
+    );
+    outbounds.push(json!({ "type": "direct", "tag": "direct" }));
+
+    serde_json::to_string_pretty(&json!({ "outbounds": outbounds })).unwrap_or_default()
+}
+
+fn render_xray(shares: &[Share]) -> String {
+    let names = names(shares);
+    let outbounds: Vec<Value> = shares
+        .iter()
+        .zip(names)
+        .filter_map(|(share, tag)| {
+            let (protocol, settings) = match share.inbound.protocol {
+                Protocol::Vless => (
+                    "vless",
+                    json!({ "vnext": [{
+                        "address": share.address,
+                        "port": share.port,
+                        "users": [{ "id": share.user.uuid, "encryption": "none", "level": 0 }],
+                    }]}),
+                ),
+                Protocol::Vmess => (
+                    "vmess",
+                    json!({ "vnext": [{
+                        "address": share.address,
+                        "port": share.port,
+                        "users": [{ "id": share.user.uuid, "security": "zero" }],
+                    }]}),
+                ),
+                Protocol::Trojan => (
+                    "trojan",
+                    json!({ "servers": [{
+                        "address": share.address,
+                        "port": share.port,
+                        "password": share.user.password,
+                    }]}),
+                ),
+                _ => return None,
+            };
+
+            let mut tls = json!({ "serverName": share.sni });
+            if let Some(fp) = &share.fingerprint {
+                merge(&mut tls, json!({ "fingerprint": fp }));
+            }
+            if !share.alpn.is_empty() {
+                merge(&mut tls, json!({ "alpn": share.alpn }));
+            }
+
+            let network = share.inbound.transport.network();
+            let transport = match share.inbound.transport {
+                InboundTransport::Ws => json!({
+                    "wsSettings": { "path": share.inbound.path, "headers": { "Host": share.host } },
+                }),
+                InboundTransport::SplitHttp => json!({
+                    "splithttpSettings": { "path": share.inbound.path, "host": share.host },
+                }),
+                InboundTransport::Grpc => json!({
+                    "grpcSettings": { "serviceName": share.inbound.service_name() },
+                }),
+                InboundTransport::HttpUpgrade => json!({
+                    "httpupgradeSettings": { "path": share.inbound.path, "host": share.host },
+                }),
+            };
+            let mut stream = json!({
+                "security": "tls",
+                "network": network,
+                "tlsSettings": tls,
+            });
+            merge(&mut stream, transport);
+
+            Some(json!({
+                "tag": tag,
+                "protocol": protocol,
+                "settings": settings,
+                "streamSettings": stream,
+            }))
+        })
+        .collect();
+
+    let config = json!({
+        "log": { "loglevel": "info" },
+        "inbounds": [{
+            "port": 1085,
+            "listen": "127.0.0.1",
+            "protocol": "socks",
+            "settings": { "udp": true },
+        }],
+        "outbounds": outbounds,
+    });
+    serde_json::to_string_pretty(&config).unwrap_or_default()
+}
+
+fn merge(target: &mut Value, fields: Value) {
+    if let (Value::Object(target), Value::Object(fields)) = (target, fields) {
+        target.extend(fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config::new(
+            r#"
+            [[inbound]]
+            protocol = "vless"
+            uuid = "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8"
+            path = "/vless"
+            users = [{ name = "alice", uuid = "96850032-1b92-46e9-a4f2-b99631456894", sub_token = "t0k3n" }]
+
+            [[inbound]]
+            protocol = "trojan"
+            path = "/trojan"
+            users = [{ name = "alice", password = "secret", sub_token = "t0k3n" }]
+
+            [[inbound]]
+            protocol = "bepass"
+            path = "/bepass"
+
+            [outbound]
+            protocol = "freedom"
+            match = []
+            "#,
+        )
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Format::negotiate(Some("clash"), ""), Format::Clash);
+        assert_eq!(
+            Format::negotiate(Some("unknown"), "sing-box 1.8.0"),
+            Format::SingBox
+        );
+        assert_eq!(Format::negotiate(None, "ClashMeta/1.18"), Format::Clash);
+        assert_eq!(Format::negotiate(None, "v2rayN/6.42"), Format::Base64);
+        assert_eq!(Format::negotiate(Some("xray"), ""), Format::Xray);
+    }
+
+    #[test]
+    fn test_render() {
+        let config = config();
+        let shares = link::shares(&config, "example.com", |user| user.sub_token == "t0k3n");
+        assert_eq!(owners(&config, "t0k3n").len(), 2);
+        assert!(owners(&config, "").is_empty());
+
+        // two users owning the token plus the unauthenticated bepass inbound
+        assert_eq!(shares.len(), 3);
+
+        let links =
+            String::from_utf8(STANDARD.decode(render(&Format::Base64, &shares)).unwrap()).unwrap();
+        assert_eq!(links.lines().count(), 3);
+        assert!(links.contains("vless://96850032-1b92-46e9-a4f2-b99631456894@example.com:443"));
+        assert!(links.contains("https://example.com:443/bepass"));
+
+        let clash = render(&Format::Clash, &shares);
+        assert!(clash.contains(r#""name":"tunl-alice #2""#));
+        assert!(clash.contains(r#""type":"trojan""#));
+        assert!(clash.ends_with("rules:\n  - MATCH,tunl\n"));
+
+        let singbox: Value = serde_json::from_str(&render(&Format::SingBox, &shares)).unwrap();
+        assert_eq!(
+            singbox["outbounds"][0]["outbounds"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(singbox["outbounds"][1]["type"], "vless");
+
+        let xray: Value = serde_json::from_str(&render(&Format::Xray, &shares)).unwrap();
+        assert_eq!(xray["outbounds"][1]["protocol"], "trojan");
+        assert_eq!(
+            xray["outbounds"][1]["settings"]["servers"][0]["password"],
+            "secret"
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use crate::common::time;
+use crate::config::{Inbound, Quota, User};
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+const DURABLE_OBJECT_BINDING: &str = "TRAFFIC";
+const STATS_KEY: &str = "stats";
+const RESERVATIONS_KEY: &str = "reservations";
+
+// the budget of a connection which stopped reporting goes back to the others
+const RESERVATION_TTL: u64 = 5 * 60 * 1000;
+
+#[derive(Default, Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct Usage {
+    pub upload: u64,
+    pub download: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.upload.saturating_add(self.download)
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.upload = self.upload.saturating_add(other.upload);
+        self.download = self.download.saturating_add(other.download);
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub total: Usage,
+    pub monthly: Usage,
+    /// calendar month of the monthly usage (E.g. 2024-05)
+    pub period: String,
+}
+
+impl Stats {
+    /// starts over the monthly usage once a new month begins
+    fn roll(&mut self, period: &str) {
+        if self.period != period {
+            self.period = period.to_string();
+            self.monthly = Usage::default();
+        }
+    }
+
+    fn add(&mut self, usage: &Usage) {
+        self.total.add(usage);
+        self.monthly.add(usage);
+    }
+
+    /// returns the bytes left before hitting the quota, `None` means unlimited
+    pub fn remaining(&self, quota: &Quota, period: &str) -> Option<u64> {
+        let monthly = match self.period == period {
+            true => self.monthly.total(),
+            false => 0,
+        };
+
+        [(quota.monthly, monthly), (quota.total, self.total.total())]
+            .into_iter()
+            .filter(|(limit, _)| *limit > 0)
+            .map(|(limit, used)| limit.saturating_sub(used))
+            .min()
+    }
+}
+
+/// usage of a connection since its previous report, along with the budget
+/// it wants to use before the next one
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    /// connection the budget is reserved for, it replaces its previous reservation
+    pub id: String,
+    pub usage: Usage,
+    /// bytes to reserve, 0 releases the reservation of the connection
+    pub reserve: u64,
+    pub quota: Quota,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    pub stats: Stats,
+    /// bytes reserved for the connection, `None` means unlimited
+    pub granted: Option<u64>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Reservation {
+    bytes: u64,
+    expires: u64,
+}
+
+/// books the usage of a report and reserves the budget it asks for, the
+/// budget reserved by the other connections of the counter is held back
+fn settle(
+    stats: &mut Stats,
+    reservations: &mut BTreeMap<String, Reservation>,
+    report: &Report,
+    period: &str,
+    now: u64,
+) -> Option<u64> {
+    reservations.retain(|id, reservation| *id != report.id && reservation.expires > now);
+    stats.add(&report.usage);
+
+    let reserved: u64 = reservations.values().map(|r| r.bytes).sum();
+    let granted = stats
+        .remaining(&report.quota, period)
+        .map(|remaining| report.reserve.min(remaining.saturating_sub(reserved)));
+    if let Some(bytes @ 1..) = granted {
+        reservations.insert(
+            report.id.clone(),
+            Reservation {
+                bytes,
+                expires: now + RESERVATION_TTL,
+            },
+        );
+    }
+    granted
+}
+
+pub fn user_key(inbound: &Inbound, user: &User) -> String {
+    format!("user:{}:{}", inbound.path, user.name)
+}
+
+pub fn inbound_key(inbound: &Inbound) -> String {
+    format!("inbound:{}", inbound.path)
+}
+
+/// formats a unix timestamp (in milliseconds) as a calendar month
+pub fn period(millis: u64) -> String {
+    let (year, month, _) = time::date(millis);
+    format!("{year:04}-{month:02}")
+}
+
+pub fn current_period() -> String {
+    period(time::now())
+}
+
+/// client of the traffic counters, each counter lives in its own durable object
+#[derive(Clone)]
+pub struct Traffic {
+    namespace: Rc<ObjectNamespace>,
+}
+
+impl Traffic {
+    /// returns `None` if the durable object binding is not configured
+    pub fn new(env: &Env) -> Option<Self> {
+        env.durable_object(DURABLE_OBJECT_BINDING)
+            .ok()
+            .map(|namespace| Self {
+                namespace: Rc::new(namespace),
+            })
+    }
+
+    pub async fn stats(&self, key: &str) -> Result<Stats> {
+        let stub = self.namespace.id_from_name(key)?.get_stub()?;
+        stub.fetch_with_str("https://traffic/").await?.json().await
+    }
+
+    pub async fn report(&self, key: &str, report: &Report) -> Result<Grant> {
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_body(Some(serde_json::to_string(report)?.into()));
+
+        let req = Request::new_with_init("https://traffic/", &init)?;
+        let stub = self.namespace.id_from_name(key)?.get_stub()?;
+        stub.fetch_with_request(req).await?.json().await
+    }
+}
+
+#[durable_object]
+pub struct TrafficCounter {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for TrafficCounter {
+    fn new(state: State, _: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let mut storage = self.state.storage();
+        let mut stats = storage.get::<Stats>(STATS_KEY).await.unwrap_or_default();
+        let period = current_period();
+        stats.roll(&period);
+
+        if req.method() != Method::Post {
+            return Response::from_json(&stats);
+        }
+
+        let report = req.json::<Report>().await?;
+        let mut reservations = storage
+            .get::<BTreeMap<String, Reservation>>(RESERVATIONS_KEY)
+            .await
+            .unwrap_or_default();
+        let granted = settle(&mut stats, &mut reservations, &report, &period, time::now());
+        storage.put(STATS_KEY, &stats).await?;
+        storage.put(RESERVATIONS_KEY, &reservations).await?;
+
+        Response::from_json(&Grant { stats, granted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period() {
+        assert_eq!(period(0), "1970-01");
+        // 2024-02-29T23:59:59Z
+        assert_eq!(period(1_709_251_199_000), "2024-02");
+        // 2024-03-01T00:00:00Z
+        assert_eq!(period(1_709_251_200_000), "2024-03");
+        // 2026-12-31T12:00:00Z
+        assert_eq!(period(1_798_718_400_000), "2026-12");
+    }
+
+    #[test]
+    fn test_remaining() {
+        let mut stats = Stats::default();
+        stats.roll("2024-05");
+        stats.add(&Usage {
+            upload: 100,
+            download: 400,
+        });
+
+        let unlimited = Quota::default();
+        assert_eq!(stats.remaining(&unlimited, "2024-05"), None);
+
+        let quota = Quota {
+            monthly: 1000,
+            total: 0,
+        };
+        assert_eq!(stats.remaining(&quota, "2024-05"), Some(500));
+        assert_eq!(stats.remaining(&quota, "2024-06"), Some(1000));
+
+        let quota = Quota {
+            monthly: 1000,
+            total: 600,
+        };
+        assert_eq!(stats.remaining(&quota, "2024-06"), Some(100));
+
+        stats.roll("2024-06");
+        assert_eq!(stats.monthly, Usage::default());
+        assert_eq!(stats.total.total(), 500);
+    }
+
+    #[test]
+    fn test_settle() {
+        let mut stats = Stats::default();
+        stats.roll("2024-05");
+        let mut reservations = BTreeMap::new();
+        let report = |id: &str, usage: u64, reserve: u64| Report {
+            id: id.to_string(),
+            usage: Usage {
+                upload: usage,
+                download: 0,
+            },
+            reserve,
+            quota: Quota {
+                monthly: 1000,
+                total: 0,
+            },
+        };
+        let mut settle = |report: Report, now: u64| {
+            settle(&mut stats, &mut reservations, &report, "2024-05", now)
+        };
+
+        // concurrent connections share what's left instead of each getting all of it
+        assert_eq!(settle(report("a", 0, 400), 0), Some(400));
+        assert_eq!(settle(report("b", 0, 400), 0), Some(400));
+        assert_eq!(settle(report("c", 0, 400), 0), Some(200));
+        assert_eq!(settle(report("d", 0, 400), 0), Some(0));
+
+        // a report replaces the reservation of its connection
+        assert_eq!(settle(report("a", 400, 400), 1), Some(0));
+        assert_eq!(settle(report("c", 150, 0), 1), Some(0));
+        assert_eq!(settle(report("a", 0, 400), 1), Some(50));
+
+        // the reservation of a connection which stopped reporting expires
+        assert_eq!(settle(report("d", 0, 400), RESERVATION_TTL - 1), Some(0));
+        assert_eq!(settle(report("d", 0, 400), RESERVATION_TTL), Some(400));
+
+        // counters without a quota only book the usage
+        let unlimited = Report {
+            quota: Quota::default(),
+            ..report("e", 50, 400)
+        };
+        assert_eq!(settle(unlimited, RESERVATION_TTL), None);
+        assert_eq!(stats.monthly.total(), 600);
+    }
+}
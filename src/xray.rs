@@ -0,0 +1,411 @@
+use crate::config::{
+    Config, Inbound, InboundTransport, Outbound, Protocol, Rule, SecureTransport, Transport, User,
+};
+use crate::import;
+use crate::link;
+use crate::subscription::{self, Format};
+
+use cidr::IpCidr;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// converts the `inbounds`/`outbounds`/`routing` of an xray config into a tunl
+/// config, everything which can't be mapped is reported as a warning
+pub fn import(xray: &Value) -> (Config, Vec<String>) {
+    let mut warnings = Vec::new();
+    let mut config = Config::default();
+
+    for (i, inbound) in array(&xray["inbounds"]).iter().enumerate() {
+        match import_inbound(inbound, &mut warnings) {
+            Some(inbound) => config.inbound.push(inbound),
+            None => warnings.push(format!("inbounds[{i}]: skipped")),
+        }
+    }
+
+    let mut outbounds: Vec<Outbound> = array(&xray["outbounds"])
+        .iter()
+        .filter_map(|outbound| import_outbound(outbound, &mut warnings))
+        .collect();
+
+    // the first outbound the routing rules point to becomes the main one
+    let routed: Vec<&str> = xray["routing"]["rules"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|rule| rule["outboundTag"].as_str())
+        .collect();
+    let main = outbounds
+        .iter()
+        .position(|o| !o.tag.is_empty() && routed.contains(&o.tag.as_str()))
+        .or((!outbounds.is_empty()).then_some(0));
+    config.outbound = match main {
+        Some(i) => outbounds.remove(i),
+        None => {
+            warnings.push("no supported outbound, using freedom".to_string());
+            Outbound {
+                protocol: Protocol::Freedom,
+                ..Default::default()
+            }
+        }
+    };
+    config.outbounds = outbounds;
+    import_routing(&xray["routing"], &mut config, &mut warnings);
+
+    (config, warnings)
+}
+
+/// renders a client config connecting to every inbound served at `host`
+pub fn export(config: &Config, host: &str) -> String {
+    let shares = link::shares(config, host, |_| true);
+    subscription::render(&Format::Xray, &shares)
+}
+
+fn array(value: &Value) -> Vec<Value> {
+    value.as_array().cloned().unwrap_or_default()
+}
+
+fn protocol(name: &str) -> Option<Protocol> {
+    match name {
+        "vmess" => Some(Protocol::Vmess),
+        "vless" => Some(Protocol::Vless),
+        "trojan" => Some(Protocol::Trojan),
+        "shadowsocks" => Some(Protocol::Shadowsocks),
+        "freedom" => Some(Protocol::Freedom),
+        "blackhole" => Some(Protocol::Blackhole),
+        _ => None,
+    }
+}
+
+fn parse_uuid(value: &Value, warnings: &mut Vec<String>) -> Uuid {
+    let id = value.as_str().unwrap_or_default();
+    Uuid::parse_str(id).unwrap_or_else(|_| {
+        warnings.push(format!("invalid uuid: {id}"));
+        Uuid::nil()
+    })
+}
+
+/// tunl only serves websocket, splithttp, grpc and httpupgrade inbounds behind
+/// the tls of cloudflare, returns the transport along with the key of its settings
+fn check_stream(
+    stream: &Value,
+    tag: &str,
+    warnings: &mut Vec<String>,
+) -> (InboundTransport, &'static str) {
+    let transport = match stream["network"].as_str().unwrap_or("tcp") {
+        "ws" => (InboundTransport::Ws, "wsSettings"),
+        "splithttp" => (InboundTransport::SplitHttp, "splithttpSettings"),
+        "xhttp" => (InboundTransport::SplitHttp, "xhttpSettings"),
+        "grpc" => (InboundTransport::Grpc, "grpcSettings"),
+        "httpupgrade" => (InboundTransport::HttpUpgrade, "httpupgradeSettings"),
+        network => {
+            warnings.push(format!(
+                "{tag}: network {network} is not supported, using ws"
+            ));
+            (InboundTransport::Ws, "wsSettings")
+        }
+    };
+    if let Some(security) = stream["security"].as_str() {
+        if !matches!(security, "none" | "tls") {
+            warnings.push(format!("{tag}: security {security} is not supported"));
+        }
+    }
+    transport
+}
+
+fn import_inbound(inbound: &Value, warnings: &mut Vec<String>) -> Option<Inbound> {
+    let name = inbound["protocol"].as_str().unwrap_or_default();
+    let tag = inbound["tag"].as_str().unwrap_or(name).to_string();
+    let protocol = match protocol(name) {
+        Some(p @ (Protocol::Vmess | Protocol::Vless | Protocol::Trojan)) => p,
+        _ => {
+            warnings.push(format!("{tag}: inbound protocol {name} is not supported"));
+            return None;
+        }
+    };
+
+    let stream = &inbound["streamSettings"];
+    let (transport, settings) = check_stream(stream, &tag, warnings);
+    let path = match (transport, &stream[settings]) {
+        (InboundTransport::Grpc, settings) => settings["serviceName"]
+            .as_str()
+            .map(|service| format!("/{}", service.trim_start_matches('/'))),
+        (_, settings) => settings["path"].as_str().map(String::from),
+    };
+    let path = match path {
+        Some(path) => path,
+        None => {
+            warnings.push(format!("{tag}: no path, using /{name}"));
+            format!("/{name}")
+        }
+    };
+
+    let users = array(&inbound["settings"]["clients"])
+        .iter()
+        .enumerate()
+        .map(|(i, client)| User {
+            name: client["email"]
+                .as_str()
+                .map(String::from)
+                .unwrap_or(format!("user{}", i + 1)),
+            uuid: match protocol {
+                Protocol::Trojan => Uuid::nil(),
+                _ => parse_uuid(&client["id"], warnings),
+            },
+            password: client["password"].as_str().unwrap_or_default().to_string(),
+            ..Default::default()
+        })
+        .collect();
+
+    Some(Inbound {
+        protocol,
+        path,
+        transport,
+        users,
+        ..Default::default()
+    })
+}
+
+/// converts a single outbound, `None` if tunl can't connect through its protocol
+fn import_outbound(outbound: &Value, warnings: &mut Vec<String>) -> Option<Outbound> {
+    let name = outbound["protocol"].as_str().unwrap_or_default();
+    let tag = outbound["tag"].as_str().unwrap_or_default().to_string();
+    let protocol = match protocol(name) {
+        Some(protocol) if protocol.has_outbound() => protocol,
+        _ => {
+            warnings.push(format!(
+                "{}: outbound protocol {name} is not supported",
+                match tag.as_str() {
+                    "" => name,
+                    tag => tag,
+                }
+            ));
+            return None;
+        }
+    };
+
+    let stream = &outbound["streamSettings"];
+    let transport = match stream["network"].as_str() {
+        None | Some("tcp") => Transport::Tcp,
+        Some("ws") => Transport::Ws,
+        Some(network) => {
+            warnings.push(format!("{name}: outbound transport {network} is ignored"));
+            Transport::Tcp
+        }
+    };
+    let ws = &stream["wsSettings"];
+    let ws = import::ws_transport(
+        ws["path"].as_str().map(String::from),
+        ws["headers"]["Host"].as_str().map(String::from),
+    );
+    let secure_transport = match stream["security"].as_str() {
+        Some("tls") => SecureTransport::On,
+        Some("none") | None => SecureTransport::Off,
+        Some(security) => {
+            warnings.push(format!("{name}: security {security} is not supported"));
+            SecureTransport::Off
+        }
+    };
+
+    let settings = &outbound["settings"];
+    let server = match &settings["vnext"][0] {
+        Value::Null => &settings["servers"][0],
+        vnext => vnext,
+    };
+    let user = &server["users"][0];
+
+    Some(Outbound {
+        tag,
+        protocol,
+        addresses: server["address"]
+            .as_str()
+            .map(|a| vec![a.to_string()])
+            .unwrap_or_default(),
+        port: server["port"].as_u64().unwrap_or_default() as u16,
+        uuid: match user["id"] {
+            Value::Null => Uuid::nil(),
+            ref id => parse_uuid(id, warnings),
+        },
+        password: server["password"].as_str().unwrap_or_default().to_string(),
+        method: server["method"].as_str().unwrap_or_default().to_string(),
+        secure_transport,
+        sni: stream["tlsSettings"]["serverName"]
+            .as_str()
+            .map(String::from),
+        transport,
+        ws,
+        ..Default::default()
+    })
+}
+
+/// routes the ip ranges of the rules to the imported outbounds, the ranges
+/// of the main outbound are its `match` list
+fn import_routing(routing: &Value, config: &mut Config, warnings: &mut Vec<String>) {
+    for rule in array(&routing["rules"]) {
+        let Some(tag) = rule["outboundTag"].as_str() else {
+            if let Some(balancer) = rule["balancerTag"].as_str() {
+                warnings.push(format!("routing: balancer {balancer} is not supported"));
+            }
+            continue;
+        };
+        let main = config.outbound.tag == tag;
+        if !main && config.outbound_by_tag(tag).is_none() {
+            warnings.push(format!("routing: outbound {tag} was not imported"));
+            continue;
+        }
+
+        for field in ["domain", "port", "source", "network", "protocol"] {
+            if !rule[field].is_null() {
+                warnings.push(format!("routing: {field} rules are not supported"));
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for ip in array(&rule["ip"]) {
+            let ip = ip.as_str().unwrap_or_default();
+            match ip.parse::<IpCidr>() {
+                Ok(cidr) => ranges.push(cidr),
+                Err(_) => warnings.push(format!("routing: unsupported ip rule {ip}")),
+            }
+        }
+
+        match main {
+            true => config.outbound.r#match.extend(ranges),
+            false if !ranges.is_empty() => config.routing.push(Rule {
+                r#match: ranges,
+                outbound: tag.to_string(),
+            }),
+            false => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_import() {
+        let xray = json!({
+            "inbounds": [
+                {
+                    "protocol": "vless",
+                    "settings": { "clients": [
+                        { "id": "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8", "email": "alice" },
+                        { "id": "1fbf4f81-2598-4b6a-a623-0ead4cb9efa8" },
+                    ]},
+                    "streamSettings": { "network": "ws", "wsSettings": { "path": "/vless" } },
+                },
+                {
+                    "protocol": "trojan",
+                    "settings": { "clients": [{ "password": "secret" }] },
+                    "streamSettings": { "network": "grpc", "grpcSettings": { "serviceName": "tun" } },
+                },
+                {
+                    "protocol": "vmess",
+                    "settings": { "clients": [] },
+                    "streamSettings": { "network": "kcp" },
+                },
+                {
+                    "protocol": "vless",
+                    "settings": { "clients": [{ "id": "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8" }] },
+                    "streamSettings": { "network": "xhttp", "xhttpSettings": { "path": "/split" } },
+                },
+                { "protocol": "socks", "port": 1080 },
+            ],
+            "outbounds": [
+                { "protocol": "freedom", "tag": "direct" },
+                {
+                    "protocol": "vless",
+                    "tag": "relay",
+                    "settings": { "vnext": [{
+                        "address": "1.1.1.1",
+                        "port": 6666,
+                        "users": [{ "id": "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8" }],
+                    }]},
+                    "streamSettings": {
+                        "network": "ws",
+                        "wsSettings": { "path": "/relay?ed=2048", "headers": { "Host": "cdn.example.com" } },
+                    },
+                },
+                {
+                    "protocol": "trojan",
+                    "tag": "backup",
+                    "settings": { "servers": [{ "address": "2.2.2.2", "port": 443, "password": "secret" }] },
+                    "streamSettings": { "security": "tls" },
+                },
+                {
+                    "protocol": "vmess",
+                    "tag": "legacy",
+                    "settings": { "vnext": [{ "address": "3.3.3.3", "port": 443, "users": [] }] },
+                },
+            ],
+            "routing": { "rules": [
+                {
+                    "type": "field",
+                    "ip": ["104.16.0.0/13", "geoip:private"],
+                    "outboundTag": "relay",
+                },
+                { "type": "field", "ip": ["8.8.8.0/24"], "outboundTag": "backup" },
+                { "type": "field", "ip": ["9.9.9.0/24"], "outboundTag": "legacy" },
+            ]},
+        });
+
+        let (config, warnings) = import(&xray);
+        assert_eq!(config.inbound.len(), 4);
+        assert_eq!(config.inbound[0].users[0].name, "alice");
+        assert_eq!(config.inbound[0].users[1].name, "user2");
+        assert_eq!(config.inbound[1].path, "/tun");
+        assert_eq!(config.inbound[1].transport, InboundTransport::Grpc);
+        assert_eq!(config.inbound[1].users[0].password, "secret");
+        assert_eq!(config.inbound[3].transport, InboundTransport::SplitHttp);
+        assert_eq!(config.inbound[3].path, "/split");
+
+        assert_eq!(config.outbound.protocol, Protocol::Vless);
+        assert_eq!(config.outbound.addresses, vec!["1.1.1.1"]);
+        assert_eq!(config.outbound.port, 6666);
+        assert_eq!(config.outbound.r#match.len(), 1);
+        assert_eq!(config.outbound.transport, Transport::Ws);
+        assert_eq!(config.outbound.ws.path, "/relay");
+        assert_eq!(config.outbound.ws.early_data, 2048);
+        assert_eq!(config.outbound.ws.host.as_deref(), Some("cdn.example.com"));
+
+        // the other outbounds are reached through routing rules
+        let tags: Vec<&str> = config.outbounds.iter().map(|o| o.tag.as_str()).collect();
+        assert_eq!(tags, ["direct", "backup"]);
+        assert_eq!(config.outbounds[1].password, "secret");
+        assert_eq!(config.routing.len(), 1);
+        assert_eq!(config.routing[0].outbound, "backup");
+        assert!(config.validate().is_empty());
+
+        assert!(warnings
+            .iter()
+            .any(|w| w == "legacy: outbound protocol vmess is not supported"));
+        assert!(warnings
+            .iter()
+            .any(|w| w == "routing: outbound legacy was not imported"));
+        assert!(warnings.iter().any(|w| w.contains("kcp")));
+        assert!(warnings.iter().any(|w| w.contains("socks")));
+        assert!(warnings.iter().any(|w| w.contains("geoip:private")));
+    }
+
+    #[test]
+    fn test_export() {
+        let (config, _) = import(&json!({
+            "inbounds": [{
+                "protocol": "vmess",
+                "settings": { "clients": [{ "id": "0fbf4f81-2598-4b6a-a623-0ead4cb9efa8" }] },
+                "streamSettings": { "network": "ws", "wsSettings": { "path": "/vmess" } },
+            }],
+        }));
+
+        let exported: Value = serde_json::from_str(&export(&config, "tunl.workers.dev")).unwrap();
+        let outbound = &exported["outbounds"][0];
+        assert_eq!(outbound["protocol"], "vmess");
+        assert_eq!(
+            outbound["settings"]["vnext"][0]["address"],
+            "tunl.workers.dev"
+        );
+        assert_eq!(outbound["streamSettings"]["wsSettings"]["path"], "/vmess");
+    }
+}
@@ -0,0 +1,56 @@
+#![allow(unused)]
+
+mod admin;
+mod common;
+mod config;
+mod import;
+mod limit;
+mod link;
+mod proxy;
+mod qr;
+mod subscription;
+mod traffic;
+mod xray;
+
+use crate::config::Config;
+
+use std::process::exit;
+
+const USAGE: &str = "usage:
+    xray_converter import <xray.json>           prints the equivalent tunl config
+    xray_converter export <config.toml> <host>  prints an xray client config";
+
+fn read(path: &str) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("failed to read {path}: {e}");
+        exit(1);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["import", path] => {
+            let xray = serde_json::from_str(&read(path)).unwrap_or_else(|e| {
+                eprintln!("invalid xray config: {e}");
+                exit(1);
+            });
+
+            let (config, warnings) = xray::import(&xray);
+            for warning in warnings {
+                eprintln!("warning: {warning}");
+            }
+            print!("{}", toml::to_string_pretty(&config).unwrap());
+        }
+        ["export", path, host] => {
+            let config = Config::new(&read(path));
+            println!("{}", xray::export(&config, host));
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            exit(2);
+        }
+    }
+}